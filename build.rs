@@ -0,0 +1,90 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+const TARGET: &str = "riscv64imac-unknown-none-elf";
+
+/// Size of the symbol blob, the same as `SYMBOLS_SIZE` in `src/backtrace.rs`
+const SYMBOLS_SIZE: usize = 256 * 1024;
+
+/// Embed the text symbols of the `nm -n` output named by `RV_CSR_SYMBOLS` so
+/// backtraces can be symbolized. The justfile links once, runs `nm`, and
+/// builds again with the variable set. Both passes embed a zero-padded blob
+/// of the same size, so the second link has the same layout as the first.
+fn symbols(out_dir: &Path) {
+    println!("cargo:rerun-if-env-changed=RV_CSR_SYMBOLS");
+    let mut blob = match env::var("RV_CSR_SYMBOLS") {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={}", path);
+            let nm = fs::read_to_string(path).unwrap_or_default();
+            nm.lines()
+                .filter(|line| matches!(line.split(' ').nth(1), Some("T") | Some("t")))
+                .flat_map(|line| line.bytes().chain(Some(b'\n')))
+                .collect()
+        }
+        Err(_) => Vec::new(),
+    };
+    assert!(
+        blob.len() <= SYMBOLS_SIZE,
+        "{} bytes of symbols do not fit in SYMBOLS_SIZE",
+        blob.len()
+    );
+    blob.resize(SYMBOLS_SIZE, 0);
+    fs::write(out_dir.join("symbols.bin"), blob).unwrap();
+}
+
+/// `<target dir>/<triple>/<profile>`, where the user programs end up
+fn elf_dir(out_dir: &Path) -> PathBuf {
+    println!("cargo:rerun-if-env-changed=CARGO_TARGET_DIR");
+    match env::var_os("CARGO_TARGET_DIR") {
+        Some(dir) => PathBuf::from(dir)
+            .join(TARGET)
+            .join(env::var("PROFILE").unwrap()),
+        // OUT_DIR is `<profile dir>/build/<package>-<hash>/out`
+        None => out_dir.ancestors().nth(3).unwrap().to_owned(),
+    }
+}
+
+/// Embed every program of `user/src/bin` that has already been built with
+/// the same profile, see the `user` recipe of the justfile.
+fn apps(out_dir: &Path) {
+    println!("cargo:rerun-if-changed=user/src/bin");
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let elf_dir = elf_dir(out_dir);
+    let mut names: Vec<String> = fs::read_dir(manifest_dir.join("user/src/bin"))
+        .unwrap()
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            match path.extension() {
+                Some(ext) if ext == "rs" => Some(path.file_stem()?.to_str()?.to_owned()),
+                _ => None,
+            }
+        })
+        .collect();
+    names.sort();
+
+    let mut table = String::from("pub static APPS: &[App] = &[\n");
+    for name in names {
+        let elf = elf_dir.join(&name);
+        println!("cargo:rerun-if-changed={}", elf.display());
+        if !elf.exists() {
+            println!("cargo:warning=user program {} not built, skipped", name);
+            continue;
+        }
+        table += &format!(
+            "    App {{ name: {:?}, elf: include_bytes!({:?}) }},\n",
+            name, elf
+        );
+    }
+    table += "];\n";
+    fs::write(out_dir.join("apps.rs"), table).unwrap();
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rustc-link-arg-bins=-Tsrc/linker.ld");
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    symbols(&out_dir);
+    apps(&out_dir);
+}
@@ -0,0 +1,112 @@
+//! Frame-pointer based backtrace.
+//!
+//! The build passes `-Cforce-frame-pointers=yes`, so every frame keeps the
+//! return address at `fp - 8` and the caller's `fp` at `fp - 16`. The walk
+//! stops as soon as `fp` leaves the known stacks.
+
+use crate::{stack, task};
+
+const MAX_DEPTH: usize = 32;
+
+/// Size of the symbol blob, the same as `SYMBOLS_SIZE` in `build.rs`
+const SYMBOLS_SIZE: usize = 256 * 1024;
+
+/// Text symbols of `nm -n` embedded by `build.rs` and padded with zeros, all
+/// zeros without the two-pass build
+static SYMBOLS: [u8; SYMBOLS_SIZE] = *include_bytes!(concat!(env!("OUT_DIR"), "/symbols.bin"));
+
+/// The blob is read through a volatile load of its address, otherwise the
+/// optimizer could fold the empty blob of the first pass and emit different
+/// code than the second.
+fn symbols() -> &'static str {
+    let blob: &'static [u8; SYMBOLS_SIZE] = unsafe { core::ptr::read_volatile(&&SYMBOLS) };
+    let len = blob.iter().position(|&b| b == 0).unwrap_or(SYMBOLS_SIZE);
+    core::str::from_utf8(&blob[..len]).unwrap_or("")
+}
+
+fn in_stack(fp: usize) -> bool {
+    extern "C" {
+        fn boot_stack();
+        fn boot_stack_top();
+    }
+    let ranges = [
+        boot_stack as usize..boot_stack_top as usize,
+        stack::KERNEL_STACK.range(),
+        stack::USER_STACK.range(),
+    ];
+    let task_stacks = task::KERNEL_STACKS
+        .iter()
+        .map(|s| s.range())
+        .chain(task::USER_STACKS.iter().map(|s| s.range()));
+    fp % 8 == 0
+        && ranges
+            .iter()
+            .cloned()
+            .chain(task_stacks)
+            .any(|r| r.start + 16 <= fp && fp <= r.end)
+}
+
+/// Nearest text symbol at or below `pc` and the offset into it
+fn symbolize(pc: usize) -> Option<(&'static str, usize)> {
+    let mut best = None;
+    for line in symbols().lines() {
+        let mut fields = line.splitn(3, ' ');
+        let (addr, kind, name) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(addr), Some(kind), Some(name)) => (addr, kind, name),
+            _ => continue,
+        };
+        if !matches!(kind, "T" | "t") {
+            continue;
+        }
+        let addr = match usize::from_str_radix(addr, 16) {
+            Ok(addr) => addr,
+            Err(_) => continue,
+        };
+        // `nm -n` sorts by address
+        if addr > pc {
+            break;
+        }
+        best = Some((name, pc - addr));
+    }
+    best
+}
+
+/// `lookup` is the address symbolized, `pc` the one printed
+fn print_frame(depth: usize, pc: usize, lookup: usize) {
+    match symbolize(lookup) {
+        Some((name, offset)) => {
+            let offset = offset + (pc - lookup);
+            println!("  #{:<2} {:#018x} {}+{:#x}", depth, pc, name, offset)
+        }
+        None => println!("  #{:<2} {:#018x}", depth, pc),
+    }
+}
+
+/// Walk the frames starting at `fp`, printing `pc` as frame 0.
+pub fn print_from(pc: usize, mut fp: usize) {
+    println!("Backtrace:");
+    print_frame(0, pc, pc);
+    for depth in 1..MAX_DEPTH {
+        if !in_stack(fp) {
+            break;
+        }
+        let (ra, prev) = unsafe { (*((fp - 8) as *const usize), *((fp - 16) as *const usize)) };
+        if ra == 0 {
+            break;
+        }
+        // ra points after the call, which may be a 2-byte `c.jalr`, so look
+        // up ra - 1 to stay inside the calling function
+        print_frame(depth, ra, ra - 1);
+        fp = prev;
+    }
+}
+
+/// Backtrace of the caller.
+#[inline(always)]
+pub fn print_backtrace() {
+    let (pc, fp): (usize, usize);
+    unsafe {
+        asm!("auipc {}, 0", "mv {}, s0", out(reg) pc, out(reg) fp);
+    }
+    print_from(pc, fp);
+}
@@ -0,0 +1,182 @@
+//! Crash report for traps nobody registered a handler for.
+//!
+//! Printed with `println!` rather than the logger so it shows up even with
+//! `LOG` unset, then the machine is shut down with a failure status so
+//! automated runs don't hang.
+
+use crate::{
+    backtrace,
+    console::ANSICON,
+    csr_probe, exit,
+    trap::{self, cause, TrapContext, UserTrapContext},
+};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering::Relaxed},
+};
+
+/// Set by [`fatal_user_trap`], which can't shut down from U-mode
+static USER_FATAL: AtomicBool = AtomicBool::new(false);
+
+const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+const STATUS_FIELDS: &[(usize, &str)] = &[
+    (0, "UIE"),
+    (1, "SIE"),
+    (4, "UPIE"),
+    (5, "SPIE"),
+    (8, "SPP"),
+    (18, "SUM"),
+    (19, "MXR"),
+    (63, "SD"),
+];
+
+const INTERRUPT_FIELDS: &[(usize, &str)] = &[
+    (0, "USI"),
+    (1, "SSI"),
+    (4, "UTI"),
+    (5, "STI"),
+    (8, "UEI"),
+    (9, "SEI"),
+];
+
+/// Set bits of a CSR by name, e.g. `0x122 [SIE SPIE SPP]`
+struct Flags(usize, &'static [(usize, &'static str)]);
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x} [", self.0)?;
+        let mut first = true;
+        for &(bit, name) in self.1 {
+            if self.0 & (1 << bit) != 0 {
+                if !first {
+                    write!(f, " ")?;
+                }
+                write!(f, "{}", name)?;
+                first = false;
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+pub fn cause_name(cause: usize) -> &'static str {
+    if cause & cause::INTERRUPT != 0 {
+        match cause & !cause::INTERRUPT {
+            0 => "UserSoft",
+            1 => "SupervisorSoft",
+            4 => "UserTimer",
+            5 => "SupervisorTimer",
+            8 => "UserExternal",
+            9 => "SupervisorExternal",
+            _ => "UnknownInterrupt",
+        }
+    } else {
+        match cause {
+            0 => "InstructionMisaligned",
+            1 => "InstructionFault",
+            2 => "IllegalInstruction",
+            3 => "Breakpoint",
+            4 => "LoadMisaligned",
+            5 => "LoadFault",
+            6 => "StoreMisaligned",
+            7 => "StoreFault",
+            8 => "UserEnvCall",
+            9 => "SupervisorEnvCall",
+            11 => "MachineEnvCall",
+            12 => "InstructionPageFault",
+            13 => "LoadPageFault",
+            15 => "StorePageFault",
+            _ => "UnknownException",
+        }
+    }
+}
+
+fn dump_registers(x: &[usize; 32]) {
+    for (i, chunk) in x.chunks(4).enumerate() {
+        for (j, value) in chunk.iter().enumerate() {
+            print!("{:>5}: {:#018x} ", ABI_NAMES[i * 4 + j], value);
+        }
+        println!("");
+    }
+}
+
+/// `None` if the CSR does not exist on this core
+fn try_read(name: &str) -> Option<usize> {
+    let entry = csr_probe::CSR_TABLE.iter().find(|e| e.name == name)?;
+    csr_probe::try_access(entry.read)
+}
+
+fn dump_user_csrs() {
+    match (try_read("ustatus"), try_read("uie"), try_read("uip")) {
+        (Some(ustatus), Some(uie), Some(uip)) => {
+            println!("ustatus: {}", Flags(ustatus, STATUS_FIELDS));
+            println!("    uie: {}", Flags(uie, INTERRUPT_FIELDS));
+            println!("    uip: {}", Flags(uip, INTERRUPT_FIELDS));
+        }
+        _ => println!("ustatus/uie/uip: not implemented"),
+    }
+}
+
+/// Fallback of `trap_handler`.
+pub fn fatal_trap(cx: &TrapContext, scause: usize) -> ! {
+    let (stval, sstatus, sie, sip) = unsafe {
+        (
+            read_csr!(0x143),
+            read_csr!(0x100),
+            read_csr!(0x104),
+            read_csr!(0x144),
+        )
+    };
+    println_colorized!(
+        "Fatal trap in S-mode: {} (scause = {:#x})",
+        ANSICON::FgRed,
+        ANSICON::BgDefault,
+        cause_name(scause),
+        scause
+    );
+    println!("   sepc: {:#x}", cx.sepc);
+    println!("  stval: {:#x}", stval);
+    println!("sstatus: {}", Flags(sstatus, STATUS_FIELDS));
+    println!("    sie: {}", Flags(sie, INTERRUPT_FIELDS));
+    println!("    sip: {}", Flags(sip, INTERRUPT_FIELDS));
+    dump_user_csrs();
+    dump_registers(&cx.x);
+    backtrace::print_from(cx.sepc, cx.x[8]);
+    exit::exit(1)
+}
+
+/// Fallback of `user_trap_handler`, runs in U-mode so only the U-level CSRs
+/// can be shown. Leaves U-mode through `SYSCALL_EXIT`, the kernel then shuts
+/// down in [`finish_user_fatal`].
+pub fn fatal_user_trap(cx: &UserTrapContext, ucause: usize) -> ! {
+    let utval = unsafe { read_csr!(0x043) };
+    println_colorized!(
+        "Fatal trap in U-mode: {} (ucause = {:#x})",
+        ANSICON::FgRed,
+        ANSICON::BgDefault,
+        cause_name(ucause),
+        ucause
+    );
+    println!("   uepc: {:#x}", cx.uepc);
+    println!("  utval: {:#x}", utval);
+    dump_user_csrs();
+    dump_registers(&cx.x);
+    backtrace::print_from(cx.uepc, cx.x[8]);
+    // Shutting down takes SBI calls U-mode may not make, get back to S-mode
+    // first
+    USER_FATAL.store(true, Relaxed);
+    trap::exit_user(usize::MAX)
+}
+
+/// Called in S-mode whenever U-mode exits, shuts down with a failure status
+/// if that exit came from [`fatal_user_trap`].
+pub fn finish_user_fatal() {
+    if USER_FATAL.load(Relaxed) {
+        exit::exit(1)
+    }
+}
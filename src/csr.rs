@@ -0,0 +1,40 @@
+//! Raw CSR access by 12-bit address.
+//!
+//! The `riscv` crate only wraps registers it knows about. Probing and
+//! conformance tests need to hit arbitrary addresses, so these macros emit
+//! the instruction directly. They expand to `asm!` and must be used inside
+//! an `unsafe` block.
+
+/// `csrr` from a CSR address literal, e.g. `read_csr!(0x000)`.
+#[macro_export]
+macro_rules! read_csr {
+    ($csr: literal) => {{
+        let bits: usize;
+        asm!(concat!("csrr {0}, ", stringify!($csr)), out(reg) bits);
+        bits
+    }};
+}
+
+/// `csrw` to a CSR address literal, e.g. `write_csr!(0x000, bits)`.
+#[macro_export]
+macro_rules! write_csr {
+    ($csr: literal, $bits: expr) => {
+        asm!(concat!("csrw ", stringify!($csr), ", {0}"), in(reg) ($bits) as usize)
+    };
+}
+
+/// `csrs` on a CSR address literal, sets the bits in `bits`.
+#[macro_export]
+macro_rules! set_csr {
+    ($csr: literal, $bits: expr) => {
+        asm!(concat!("csrs ", stringify!($csr), ", {0}"), in(reg) ($bits) as usize)
+    };
+}
+
+/// `csrc` on a CSR address literal, clears the bits in `bits`.
+#[macro_export]
+macro_rules! clear_csr {
+    ($csr: literal, $bits: expr) => {
+        asm!(concat!("csrc ", stringify!($csr), ", {0}"), in(reg) ($bits) as usize)
+    };
+}
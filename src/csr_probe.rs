@@ -0,0 +1,152 @@
+//! Find out which CSRs the core actually implements.
+//!
+//! Every entry in [`CSR_TABLE`] is read (and optionally written back with the
+//! value just read). An access to a missing CSR raises an illegal-instruction
+//! exception, the handler installed for the duration of the probe skips the
+//! faulting instruction instead of dying.
+
+use crate::trap::{self, cause, TrapContext};
+use core::sync::atomic::{AtomicBool, Ordering::SeqCst};
+
+static TRAPPED: AtomicBool = AtomicBool::new(false);
+
+pub struct CsrEntry {
+    pub name: &'static str,
+    pub addr: u16,
+    pub read: fn() -> usize,
+    pub write: fn(usize),
+    /// Also try to write the read value back
+    pub write_back: bool,
+}
+
+macro_rules! csr_entry {
+    ($name: literal, $addr: literal, $write_back: expr) => {
+        CsrEntry {
+            name: $name,
+            addr: $addr,
+            read: {
+                fn read() -> usize {
+                    unsafe { read_csr!($addr) }
+                }
+                read
+            },
+            write: {
+                fn write(bits: usize) {
+                    unsafe { write_csr!($addr, bits) }
+                }
+                write
+            },
+            write_back: $write_back,
+        }
+    };
+}
+
+pub static CSR_TABLE: &[CsrEntry] = &[
+    // User trap setup / handling (N extension)
+    csr_entry!("ustatus", 0x000, true),
+    csr_entry!("uie", 0x004, true),
+    csr_entry!("utvec", 0x005, true),
+    csr_entry!("uscratch", 0x040, true),
+    csr_entry!("uepc", 0x041, true),
+    csr_entry!("ucause", 0x042, true),
+    csr_entry!("utval", 0x043, true),
+    csr_entry!("uip", 0x044, true),
+    // User counters
+    csr_entry!("cycle", 0xc00, true),
+    csr_entry!("time", 0xc01, true),
+    csr_entry!("instret", 0xc02, true),
+    // Supervisor
+    csr_entry!("sstatus", 0x100, true),
+    csr_entry!("sedeleg", 0x102, true),
+    csr_entry!("sideleg", 0x103, true),
+    csr_entry!("sie", 0x104, true),
+    csr_entry!("stvec", 0x105, true),
+    csr_entry!("scounteren", 0x106, true),
+    csr_entry!("sscratch", 0x140, true),
+    csr_entry!("sepc", 0x141, true),
+    csr_entry!("scause", 0x142, true),
+    csr_entry!("stval", 0x143, true),
+    csr_entry!("sip", 0x144, true),
+    csr_entry!("satp", 0x180, true),
+    // Machine level, should always trap from S-mode
+    csr_entry!("mstatus", 0x300, false),
+    csr_entry!("misa", 0x301, false),
+    csr_entry!("mhartid", 0xf14, false),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsrStatus {
+    /// Reading traps
+    Missing,
+    /// Readable, write back not attempted
+    Readable(usize),
+    /// Readable, but writing traps
+    ReadOnly(usize),
+    ReadWrite(usize),
+}
+
+impl CsrStatus {
+    pub fn exists(&self) -> bool {
+        *self != CsrStatus::Missing
+    }
+}
+
+/// CSR instructions are never compressed, so skipping is always 4 bytes.
+fn skip_faulting(cx: &mut TrapContext, _scause: usize) {
+    TRAPPED.store(true, SeqCst);
+    cx.sepc += 4;
+}
+
+/// Run `f` with illegal-instruction traps caught, `None` if it trapped.
+pub fn try_access<T>(f: impl FnOnce() -> T) -> Option<T> {
+    TRAPPED.store(false, SeqCst);
+    let previous = trap::register(cause::ILLEGAL_INSTRUCTION, skip_faulting);
+    let ret = f();
+    trap::set_handler(cause::ILLEGAL_INSTRUCTION, previous);
+    if TRAPPED.load(SeqCst) {
+        None
+    } else {
+        Some(ret)
+    }
+}
+
+pub fn probe(entry: &CsrEntry) -> CsrStatus {
+    let bits = match try_access(entry.read) {
+        Some(bits) => bits,
+        None => return CsrStatus::Missing,
+    };
+    if !entry.write_back {
+        return CsrStatus::Readable(bits);
+    }
+    match try_access(|| (entry.write)(bits)) {
+        Some(()) => CsrStatus::ReadWrite(bits),
+        None => CsrStatus::ReadOnly(bits),
+    }
+}
+
+pub fn probe_by_name(name: &str) -> Option<CsrStatus> {
+    CSR_TABLE.iter().find(|e| e.name == name).map(probe)
+}
+
+pub fn probe_all() {
+    info!("[CSR] probing {} CSRs", CSR_TABLE.len());
+    for entry in CSR_TABLE {
+        match probe(entry) {
+            CsrStatus::Missing => {
+                info!("[CSR] {:>10} ({:#05x}): trap", entry.name, entry.addr)
+            }
+            CsrStatus::Readable(bits) => info!(
+                "[CSR] {:>10} ({:#05x}): exists   = {:#x}",
+                entry.name, entry.addr, bits
+            ),
+            CsrStatus::ReadOnly(bits) => info!(
+                "[CSR] {:>10} ({:#05x}): readonly = {:#x}",
+                entry.name, entry.addr, bits
+            ),
+            CsrStatus::ReadWrite(bits) => info!(
+                "[CSR] {:>10} ({:#05x}): rw       = {:#x}",
+                entry.name, entry.addr, bits
+            ),
+        }
+    }
+}
@@ -0,0 +1,318 @@
+//! Software emulation of the N extension for cores without it.
+//!
+//! On stock hardware every access to a user-level CSR and every `uret` raises
+//! an illegal-instruction exception. The handler installed by [`init`] decodes
+//! the instruction at `sepc` and carries it out against a shadow register
+//! file, so `__alltraps_u`/`__restore_u` run unmodified. Before every return
+//! to U-mode the shadow `uip & uie` is checked and a pending interrupt is
+//! delivered by redirecting `sepc` to the shadow `utvec`, the same way the
+//! hardware would.
+//!
+//! Exceptions from U-mode whose bit is set in the shadow `sedeleg` are
+//! redirected to `utvec` before any S-mode handler sees them. Interrupts are
+//! only taken on a trap back to U-mode, a U-mode loop that never traps does
+//! not see them.
+//!
+//! The shadow is the live register file of whatever runs in U-mode. The
+//! [`Emulated`] backend copies it to and from the task's `UserState` on every
+//! switch, `sedeleg`/`sideleg` stay global like on the hardware.
+
+use crate::{
+    trap::{self, cause, TrapContext, UserTrapContext},
+    uintr::{UserInterrupt, UserState, N_EXTENSION},
+};
+use core::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use riscv::register::{mtvec::TrapMode, stval};
+
+const UIE: usize = 1 << 0;
+const UPIE: usize = 1 << 4;
+const SPP: usize = 1 << 8;
+
+const USIP: usize = 1 << 0;
+const UTIP: usize = 1 << 4;
+const UEIP: usize = 1 << 8;
+const USER_INTERRUPTS: usize = USIP | UTIP | UEIP;
+
+const OPCODE_SYSTEM: u32 = 0x73;
+const URET: u32 = 0x0020_0073;
+
+/// Shadow copy of everything the N extension adds
+#[derive(Debug, Clone, Copy)]
+pub struct UserCsrs {
+    pub ustatus: usize,
+    pub uie: usize,
+    pub utvec: usize,
+    pub uscratch: usize,
+    pub uepc: usize,
+    pub ucause: usize,
+    pub utval: usize,
+    pub uip: usize,
+    pub sedeleg: usize,
+    pub sideleg: usize,
+}
+
+impl UserCsrs {
+    pub const fn new() -> Self {
+        Self {
+            ustatus: 0,
+            uie: 0,
+            utvec: 0,
+            uscratch: 0,
+            uepc: 0,
+            ucause: 0,
+            utval: 0,
+            uip: 0,
+            sedeleg: 0,
+            sideleg: 0,
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static mut CURRENT: UserCsrs = UserCsrs::new();
+
+pub fn enabled() -> bool {
+    ENABLED.load(Relaxed)
+}
+
+/// Shadow registers of the code currently running in U-mode
+pub fn current() -> &'static mut UserCsrs {
+    unsafe { &mut CURRENT }
+}
+
+/// Install the illegal-instruction handler if the hardware has no N extension.
+pub fn init() {
+    if crate::features::has_n_extension() {
+        info!("[EMUL] hardware N extension present, emulation disabled");
+        return;
+    }
+    *current() = UserCsrs::new();
+    trap::register(cause::ILLEGAL_INSTRUCTION, emulate);
+    ENABLED.store(true, Relaxed);
+    info!("[EMUL] emulating the N extension");
+}
+
+/// Backend while the emulation is on. U-mode reaches the shadow through the
+/// same trapping instructions as with [`N_EXTENSION`], so pending interrupts
+/// are delivered right after them. S-mode only calls access it directly.
+pub struct Emulated;
+
+impl UserInterrupt for Emulated {
+    fn name(&self) -> &'static str {
+        "N extension (emulated)"
+    }
+
+    fn delegate(&self, sources: usize) {
+        current().sideleg |= sources & USER_INTERRUPTS;
+    }
+
+    fn undelegate(&self, sources: usize) {
+        current().sideleg &= !sources;
+    }
+
+    fn set_vector(&self, handler: usize, mode: TrapMode) {
+        N_EXTENSION.set_vector(handler, mode)
+    }
+
+    fn enable(&self, sources: usize) {
+        N_EXTENSION.enable(sources)
+    }
+
+    fn disable(&self, sources: usize) {
+        N_EXTENSION.disable(sources)
+    }
+
+    fn set_global(&self, enabled: bool) {
+        N_EXTENSION.set_global(enabled)
+    }
+
+    fn pend(&self, sources: usize) {
+        N_EXTENSION.pend(sources)
+    }
+
+    fn claim(&self) -> usize {
+        N_EXTENSION.claim()
+    }
+
+    fn complete(&self, sources: usize) {
+        N_EXTENSION.complete(sources)
+    }
+
+    fn restore(&self, cx: &UserTrapContext) -> ! {
+        N_EXTENSION.restore(cx)
+    }
+
+    fn save_state(&self) -> UserState {
+        let regs = current();
+        UserState {
+            ustatus: regs.ustatus,
+            uie: regs.uie,
+            uip: regs.uip,
+            utvec: regs.utvec,
+            uscratch: regs.uscratch,
+            uepc: regs.uepc,
+            ucause: regs.ucause,
+            utval: regs.utval,
+        }
+    }
+
+    /// Unlike the hardware every `uip` bit takes, a timer or external
+    /// interrupt pended for a task stays with it.
+    fn load_state(&self, state: &UserState) {
+        let regs = current();
+        regs.ustatus = state.ustatus;
+        regs.uie = state.uie;
+        regs.uip = state.uip;
+        regs.utvec = state.utvec;
+        regs.uscratch = state.uscratch;
+        regs.uepc = state.uepc;
+        regs.ucause = state.ucause;
+        regs.utval = state.utval;
+    }
+}
+
+pub static EMULATED: Emulated = Emulated;
+
+/// CSR instructions and `uret` are never compressed
+fn fetch(pc: usize) -> Option<u32> {
+    let low = unsafe { (pc as *const u16).read_volatile() } as u32;
+    if low & 0b11 != 0b11 {
+        return None;
+    }
+    let high = unsafe { ((pc + 2) as *const u16).read_volatile() } as u32;
+    Some(low | high << 16)
+}
+
+/// The shadow register behind `csr`, with the mask of its writable bits
+fn lookup(csr: u32, from_user: bool) -> Option<(&'static mut usize, usize)> {
+    let regs = current();
+    Some(match csr {
+        0x000 => (&mut regs.ustatus, UIE | UPIE),
+        0x004 => (&mut regs.uie, USER_INTERRUPTS),
+        // Mode 0 and 1 only
+        0x005 => (&mut regs.utvec, !0b10),
+        0x040 => (&mut regs.uscratch, !0),
+        0x041 => (&mut regs.uepc, !1),
+        0x042 => (&mut regs.ucause, !0),
+        0x043 => (&mut regs.utval, !0),
+        // Timer and external can only be raised from S-mode
+        0x044 if from_user => (&mut regs.uip, USIP),
+        0x044 => (&mut regs.uip, USER_INTERRUPTS),
+        0x102 if !from_user => (&mut regs.sedeleg, !0),
+        0x103 if !from_user => (&mut regs.sideleg, USER_INTERRUPTS),
+        _ => return None,
+    })
+}
+
+/// `csrrw`/`csrrs`/`csrrc` and their immediate forms on a shadow register
+fn emulate_csr(cx: &mut TrapContext, inst: u32, from_user: bool) -> bool {
+    let funct3 = (inst >> 12) & 0b111;
+    let rd = ((inst >> 7) & 0x1f) as usize;
+    let rs1 = ((inst >> 15) & 0x1f) as usize;
+    let (reg, mask) = match lookup(inst >> 20, from_user) {
+        Some(found) => found,
+        None => return false,
+    };
+    let src = match funct3 {
+        1..=3 => cx.x[rs1],
+        5..=7 => rs1,
+        _ => return false,
+    };
+    let old = *reg;
+    let new = match funct3 & 0b11 {
+        1 => src,
+        2 => old | src,
+        _ => old & !src,
+    };
+    // csrrs/csrrc with x0 or a zero immediate do not write
+    if funct3 & 0b11 == 1 || rs1 != 0 {
+        *reg = old & !mask | new & mask;
+    }
+    if rd != 0 {
+        cx.x[rd] = old;
+    }
+    cx.sepc += 4;
+    true
+}
+
+/// `UIE <- UPIE`, `UPIE <- 1`, continue at `uepc` in U-mode
+fn emulate_uret(cx: &mut TrapContext) {
+    let regs = current();
+    let upie = regs.ustatus & UPIE != 0;
+    regs.ustatus = regs.ustatus & !UIE | UPIE | if upie { UIE } else { 0 };
+    cx.sepc = regs.uepc;
+    cx.sstatus &= !SPP;
+}
+
+fn emulate(cx: &mut TrapContext, scause: usize) {
+    let from_user = cx.sstatus & SPP == 0;
+    let handled = match fetch(cx.sepc) {
+        Some(URET) => {
+            emulate_uret(cx);
+            true
+        }
+        Some(inst) if inst & 0x7f == OPCODE_SYSTEM => emulate_csr(cx, inst, from_user),
+        _ => false,
+    };
+    if !handled && !redirect(cx, scause) {
+        crate::crash::fatal_trap(cx, scause);
+    }
+}
+
+/// Take an exception from U-mode that the shadow `sedeleg` delegates: record
+/// it in the shadow registers and continue at `utvec` in U-mode. Called by
+/// `trap_handler` before the S-mode handler, `false` if it stays in S-mode.
+pub fn delegate_exception(cx: &mut TrapContext, scause: usize) -> bool {
+    // Left to `emulate`, which redirects whatever it can't carry out
+    if scause == cause::ILLEGAL_INSTRUCTION {
+        return false;
+    }
+    redirect(cx, scause)
+}
+
+fn redirect(cx: &mut TrapContext, scause: usize) -> bool {
+    let regs = current();
+    if !enabled()
+        || scause & cause::INTERRUPT != 0
+        || cx.sstatus & SPP != 0
+        || regs.sedeleg & (1 << scause) == 0
+    {
+        return false;
+    }
+    regs.uepc = cx.sepc;
+    regs.ucause = scause;
+    regs.utval = stval::read();
+    let upie = if regs.ustatus & UIE != 0 { UPIE } else { 0 };
+    regs.ustatus = regs.ustatus & !(UIE | UPIE) | upie;
+    // Exceptions go to the base in vectored mode too
+    cx.sepc = regs.utvec & !0b11;
+    true
+}
+
+/// Take the highest priority pending user interrupt if `cx` returns to
+/// U-mode, called by `trap_handler` after the handler ran.
+pub fn deliver(cx: &mut TrapContext) {
+    if !enabled() || cx.sstatus & SPP != 0 {
+        return;
+    }
+    let regs = current();
+    if regs.ustatus & UIE == 0 {
+        return;
+    }
+    let pending = regs.uip & regs.uie & regs.sideleg;
+    // UEI > USI > UTI
+    let code = match [8, 0, 4].iter().find(|&&code| pending & (1 << code) != 0) {
+        Some(&code) => code,
+        None => return,
+    };
+    regs.uepc = cx.sepc;
+    regs.ucause = cause::INTERRUPT | code;
+    regs.utval = 0;
+    regs.ustatus = regs.ustatus & !(UIE | UPIE) | UPIE;
+    let base = regs.utvec & !0b11;
+    cx.sepc = if regs.utvec & 0b11 == 1 {
+        base + 4 * code
+    } else {
+        base
+    };
+}
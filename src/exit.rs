@@ -0,0 +1,54 @@
+//! Tell the host whether the run passed.
+//!
+//! Uses the SBI System Reset extension with a failure reason when the
+//! firmware has it, then the sifive_test finisher of the QEMU `virt` machine,
+//! and the legacy SBI shutdown as a last resort (which can't carry a status).
+
+use crate::sbi;
+use core::sync::atomic::{AtomicBool, Ordering::Relaxed};
+
+#[cfg(feature = "board_qemu")]
+const VIRT_TEST: usize = 0x10_0000;
+#[cfg(feature = "board_qemu")]
+const FINISHER_PASS: u32 = 0x5555;
+#[cfg(feature = "board_qemu")]
+const FINISHER_FAIL: u32 = 0x3333;
+
+static FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Remember a failed test, reported by [`finish`].
+pub fn record(ok: bool) {
+    if !ok {
+        FAILED.store(true, Relaxed);
+    }
+}
+
+/// Exit with 0 if nothing failed, 1 otherwise.
+pub fn finish() -> ! {
+    exit(if FAILED.load(Relaxed) { 1 } else { 0 })
+}
+
+/// Shut down, `qemu-system-riscv64` returns `code`.
+pub fn exit(code: u16) -> ! {
+    if sbi::probe_extension(sbi::SBI_EXT_SRST) {
+        let reason = if code == 0 {
+            sbi::SRST_REASON_NONE
+        } else {
+            sbi::SRST_REASON_FAILURE
+        };
+        let ret = sbi::system_reset(sbi::SRST_TYPE_SHUTDOWN, reason);
+        warn!("[EXIT] system reset failed: {:?}", ret);
+    }
+    #[cfg(feature = "board_qemu")]
+    {
+        let value = if code == 0 {
+            FINISHER_PASS
+        } else {
+            FINISHER_FAIL | (code as u32) << 16
+        };
+        unsafe {
+            (VIRT_TEST as *mut u32).write_volatile(value);
+        }
+    }
+    sbi::shutdown()
+}
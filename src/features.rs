@@ -0,0 +1,58 @@
+//! Boot-time feature detection.
+//!
+//! There is no standard SBI call that reports `misa`, and `misa` has no bit
+//! for the N extension anyway, so we look for its CSRs with trapping probes
+//! instead. Tests that need user-level interrupts are skipped when they are
+//! missing, so stock QEMU still runs the UART and S-mode tests.
+
+use crate::csr_probe::{self, CsrStatus};
+use core::sync::atomic::{AtomicBool, Ordering::Relaxed};
+
+static N_EXTENSION: AtomicBool = AtomicBool::new(false);
+
+/// CSRs the user-level interrupt code touches
+const N_EXTENSION_CSRS: &[&str] = &[
+    "ustatus", "uie", "utvec", "uscratch", "uepc", "ucause", "utval", "uip", "sedeleg", "sideleg",
+];
+
+pub fn has_n_extension() -> bool {
+    N_EXTENSION.load(Relaxed)
+}
+
+/// Hardware N extension, or the software one with the `n_emul` feature
+#[cfg(feature = "n_emul")]
+pub fn has_user_interrupts() -> bool {
+    has_n_extension() || crate::emul::enabled()
+}
+
+#[cfg(not(feature = "n_emul"))]
+pub fn has_user_interrupts() -> bool {
+    has_n_extension()
+}
+
+fn implemented(name: &str) -> bool {
+    matches!(
+        csr_probe::probe_by_name(name),
+        Some(CsrStatus::ReadWrite(_))
+    )
+}
+
+/// Probe everything once and print a capability report.
+pub fn detect() {
+    println!("[FEATURE] capability report:");
+    let mut n_extension = true;
+    for name in N_EXTENSION_CSRS {
+        let ok = implemented(name);
+        println!("[FEATURE]   {:<10} {}", name, if ok { "yes" } else { "no" });
+        n_extension &= ok;
+    }
+    N_EXTENSION.store(n_extension, Relaxed);
+    println!(
+        "[FEATURE] N extension: {}",
+        if n_extension {
+            "yes"
+        } else {
+            "no, user interrupt tests will be skipped"
+        }
+    );
+}
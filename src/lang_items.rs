@@ -1,4 +1,4 @@
-use crate::{console::ANSICON, sbi::shutdown};
+use crate::{backtrace, console::ANSICON, exit};
 use core::panic::PanicInfo;
 
 #[panic_handler]
@@ -20,5 +20,6 @@ fn panic(info: &PanicInfo) -> ! {
             info.message().unwrap()
         );
     }
-    shutdown()
+    backtrace::print_backtrace();
+    exit::exit(1)
 }
@@ -0,0 +1,171 @@
+//! Minimal ELF64 loader for the programs in `user/src/bin`.
+//!
+//! There is no paging, so `PT_LOAD` segments are copied to their physical
+//! `p_vaddr`, which has to be inside [`APP_REGION`]. The programs are linked
+//! above the kernel image, see `user/build.rs`, and only one of them is
+//! resident at a time. The segments of that one are remembered so syscalls
+//! can accept pointers into them.
+
+use crate::{stack, trap};
+use core::ops::Range;
+
+/// Embedded by `build.rs`
+pub struct App {
+    pub name: &'static str,
+    pub elf: &'static [u8],
+}
+
+include!(concat!(env!("OUT_DIR"), "/apps.rs"));
+
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+
+/// RAM left to programs, from `USER_BASE_ADDRESS` of the justfile up
+#[cfg(feature = "board_qemu")]
+pub const APP_REGION: Range<usize> = 0x8040_0000..0x8800_0000;
+#[cfg(feature = "board_lrv")]
+pub const APP_REGION: Range<usize> = 0x1_0040_0000..0x1_0080_0000;
+
+const MAX_SEGMENTS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    NotElf,
+    WrongMachine,
+    Truncated,
+    /// Segment at this address would overwrite the kernel
+    Overlap(usize),
+    /// Segment at this address is not inside [`APP_REGION`]
+    OutOfRegion(usize),
+    TooManySegments,
+}
+
+/// A `PT_LOAD` segment of the resident program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub start: usize,
+    pub end: usize,
+    flags: u32,
+}
+
+impl Segment {
+    pub fn contains(&self, range: &Range<usize>) -> bool {
+        range.start >= self.start && range.end <= self.end
+    }
+
+    pub fn executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+
+    pub fn writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+}
+
+static mut SEGMENTS: [Option<Segment>; MAX_SEGMENTS] = [None; MAX_SEGMENTS];
+
+/// Segments placed by the last successful [`load`]
+pub fn segments() -> impl Iterator<Item = Segment> {
+    unsafe { SEGMENTS.iter().flatten().copied() }
+}
+
+fn bytes<const N: usize>(elf: &[u8], offset: usize) -> Result<[u8; N], LoadError> {
+    let mut buf = [0; N];
+    let end = offset.checked_add(N).ok_or(LoadError::Truncated)?;
+    buf.copy_from_slice(elf.get(offset..end).ok_or(LoadError::Truncated)?);
+    Ok(buf)
+}
+
+fn u16_at(elf: &[u8], offset: usize) -> Result<u16, LoadError> {
+    bytes(elf, offset).map(u16::from_le_bytes)
+}
+
+fn u32_at(elf: &[u8], offset: usize) -> Result<u32, LoadError> {
+    bytes(elf, offset).map(u32::from_le_bytes)
+}
+
+fn u64_at(elf: &[u8], offset: usize) -> Result<usize, LoadError> {
+    bytes(elf, offset).map(|b| u64::from_le_bytes(b) as usize)
+}
+
+fn kernel() -> core::ops::Range<usize> {
+    extern "C" {
+        fn skernel();
+        fn ekernel();
+    }
+    skernel as usize..ekernel as usize
+}
+
+/// Copy the `PT_LOAD` segments of `elf` into place, returns the entry point.
+pub fn load(elf: &[u8]) -> Result<usize, LoadError> {
+    // 64-bit, little endian
+    if elf.get(..6) != Some(b"\x7fELF\x02\x01") {
+        return Err(LoadError::NotElf);
+    }
+    if u16_at(elf, 18)? != EM_RISCV {
+        return Err(LoadError::WrongMachine);
+    }
+    let entry = u64_at(elf, 24)?;
+    let phoff = u64_at(elf, 32)?;
+    let phentsize = u16_at(elf, 54)? as usize;
+    let phnum = u16_at(elf, 56)? as usize;
+
+    // Whatever was resident is about to be overwritten
+    unsafe { SEGMENTS = [None; MAX_SEGMENTS] };
+    let kernel = kernel();
+    let mut segments = [None; MAX_SEGMENTS];
+    let mut count = 0;
+    for i in 0..phnum {
+        let ph = i
+            .checked_mul(phentsize)
+            .and_then(|off| off.checked_add(phoff))
+            .ok_or(LoadError::Truncated)?;
+        if u32_at(elf, ph)? != PT_LOAD {
+            continue;
+        }
+        let flags = u32_at(elf, ph + 4)?;
+        let offset = u64_at(elf, ph + 8)?;
+        let vaddr = u64_at(elf, ph + 16)?;
+        let filesz = u64_at(elf, ph + 32)?;
+        let memsz = u64_at(elf, ph + 40)?;
+        let end = vaddr
+            .checked_add(memsz)
+            .ok_or(LoadError::OutOfRegion(vaddr))?;
+        if vaddr < kernel.end && end > kernel.start {
+            return Err(LoadError::Overlap(vaddr));
+        }
+        if vaddr < APP_REGION.start || end > APP_REGION.end {
+            return Err(LoadError::OutOfRegion(vaddr));
+        }
+        if filesz > memsz {
+            return Err(LoadError::Truncated);
+        }
+        let data = offset
+            .checked_add(filesz)
+            .and_then(|data_end| elf.get(offset..data_end))
+            .ok_or(LoadError::Truncated)?;
+        let slot = segments.get_mut(count).ok_or(LoadError::TooManySegments)?;
+        *slot = Some(Segment {
+            start: vaddr,
+            end,
+            flags,
+        });
+        count += 1;
+        let dst = unsafe { core::slice::from_raw_parts_mut(vaddr as *mut u8, memsz) };
+        dst[..filesz].copy_from_slice(data);
+        dst[filesz..].iter_mut().for_each(|b| *b = 0);
+    }
+    unsafe {
+        SEGMENTS = segments;
+        asm!("fence.i");
+    }
+    Ok(entry)
+}
+
+/// Load `app` and run it in U-mode on the user stack, returns its exit code.
+pub fn run(app: &App) -> Result<usize, LoadError> {
+    let entry = load(app.elf)?;
+    Ok(trap::run_user(entry, stack::USER_STACK.get_sp()))
+}
@@ -22,6 +22,14 @@ pub fn get_context(hartid: usize, mode: char) -> usize {
 pub fn handle_external_interrupt() {
     if let Some(irq) = Plic::claim(get_context(0, 'S')) {
         debug!("[PLIC] IRQ: {:?}", irq);
+        if crate::uirq::on_external(irq) {
+            // Completed by the owner task
+            return;
+        }
+        if crate::user_uart::handle_irq(irq) {
+            Plic::complete(get_context(0, 'S'), irq);
+            return;
+        }
         match irq {
             #[cfg(feature = "board_qemu")]
             10 => {
@@ -42,6 +50,34 @@ pub fn handle_external_interrupt() {
     }
 }
 
+/// Taken in U-mode: claim on the U-context, hand the IRQ to its user driver
+/// and complete it, all without a trip through S-mode.
+pub fn handle_user_external_interrupt() {
+    let context = get_context(0, 'U');
+    if let Some(irq) = Plic::claim(context) {
+        if !crate::user_uart::handle_irq(irq) {
+            warn!("[PLIC] no user driver for IRQ {}", irq);
+        }
+        Plic::complete(context, irq)
+    } else {
+        warn!("[PLIC] No pending user IRQ!");
+    }
+}
+
+/// Deliver `irq` to the context of `mode` (`'S'` or `'U'`) of hart 0 only.
+pub fn route(irq: u16, mode: char) {
+    for other in ['S', 'U'].iter().filter(|&&m| m != mode) {
+        Plic::disable(get_context(0, *other), irq);
+    }
+    Plic::set_priority(irq, Priority::lowest());
+    Plic::set_threshold(get_context(0, mode), Priority::any());
+    Plic::enable(get_context(0, mode), irq);
+}
+
+pub fn unroute(irq: u16, mode: char) {
+    Plic::disable(get_context(0, mode), irq);
+}
+
 pub fn init() {
     Plic::set_threshold(1, Priority::any());
     Plic::set_threshold(2, Priority::any());
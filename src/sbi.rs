@@ -10,6 +10,22 @@ const SBI_REMOTE_SFENCE_VMA: usize = 6;
 const SBI_REMOTE_SFENCE_VMA_ASID: usize = 7;
 const SBI_SHUTDOWN: usize = 8;
 
+const SBI_EXT_BASE: usize = 0x10;
+const SBI_BASE_PROBE_EXTENSION: usize = 3;
+pub const SBI_EXT_SRST: usize = 0x5352_5354;
+const SBI_SRST_RESET: usize = 0;
+
+pub const SRST_TYPE_SHUTDOWN: usize = 0;
+pub const SRST_REASON_NONE: usize = 0;
+pub const SRST_REASON_FAILURE: usize = 1;
+
+/// Return value of the SBI v0.2+ calling convention
+#[derive(Debug, Clone, Copy)]
+pub struct SbiRet {
+    pub error: isize,
+    pub value: usize,
+}
+
 #[inline(always)]
 pub fn sbi_call(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
     let mut ret;
@@ -24,6 +40,34 @@ pub fn sbi_call(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
     ret
 }
 
+/// SBI v0.2+ call, `fid` goes in a6 and the result comes back in a0/a1.
+#[inline(always)]
+pub fn sbi_call_ext(eid: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize) -> SbiRet {
+    let (error, value);
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+            in("a2") arg2,
+            in("a6") fid,
+            in("a7") eid,
+        );
+    }
+    SbiRet { error, value }
+}
+
+/// Legacy firmware answers with an error for the unknown base extension.
+pub fn probe_extension(eid: usize) -> bool {
+    let ret = sbi_call_ext(SBI_EXT_BASE, SBI_BASE_PROBE_EXTENSION, eid, 0, 0);
+    ret.error == 0 && ret.value != 0
+}
+
+/// Only returns if the reset failed.
+pub fn system_reset(reset_type: usize, reason: usize) -> SbiRet {
+    sbi_call_ext(SBI_EXT_SRST, SBI_SRST_RESET, reset_type, reason, 0)
+}
+
 pub fn set_timer(timer: usize) {
     sbi_call(SBI_SET_TIMER, timer, 0, 0);
 }
@@ -1,4 +1,5 @@
 use crate::trap::{TrapContext, UserTrapContext};
+use core::ops::Range;
 
 #[allow(unused)]
 const USER_STACK_SIZE: usize = 4096 * 2;
@@ -14,18 +15,22 @@ pub struct UserStack {
     data: [u8; USER_STACK_SIZE],
 }
 
-pub static KERNEL_STACK: KernelStack = KernelStack {
-    data: [0; KERNEL_STACK_SIZE],
-};
+pub static KERNEL_STACK: KernelStack = KernelStack::new();
 
-pub static USER_STACK: UserStack = UserStack {
-    data: [0; USER_STACK_SIZE],
-};
+pub static USER_STACK: UserStack = UserStack::new();
 
 impl UserStack {
+    pub const fn new() -> Self {
+        Self {
+            data: [0; USER_STACK_SIZE],
+        }
+    }
     pub fn get_sp(&self) -> usize {
         self.data.as_ptr() as usize + USER_STACK_SIZE
     }
+    pub fn range(&self) -> Range<usize> {
+        self.data.as_ptr() as usize..self.get_sp()
+    }
     pub fn push_context(&self, cx: UserTrapContext) -> &'static mut UserTrapContext {
         let cx_ptr =
             (self.get_sp() - core::mem::size_of::<UserTrapContext>()) as *mut UserTrapContext;
@@ -37,9 +42,17 @@ impl UserStack {
 }
 
 impl KernelStack {
+    pub const fn new() -> Self {
+        Self {
+            data: [0; KERNEL_STACK_SIZE],
+        }
+    }
     pub fn get_sp(&self) -> usize {
         self.data.as_ptr() as usize + KERNEL_STACK_SIZE
     }
+    pub fn range(&self) -> Range<usize> {
+        self.data.as_ptr() as usize..self.get_sp()
+    }
     pub fn push_context(&self, cx: TrapContext) -> &'static mut TrapContext {
         let cx_ptr = (self.get_sp() - core::mem::size_of::<TrapContext>()) as *mut TrapContext;
         unsafe {
@@ -0,0 +1,34 @@
+.altmacro
+.macro SAVE_SN n
+    sd s\n, (\n+2)*8(a0)
+.endm
+.macro LOAD_SN n
+    ld s\n, (\n+2)*8(a1)
+.endm
+    .section .text
+    .globl __switch
+    .globl __task_entry
+# __switch(current: *mut TaskContext, next: *const TaskContext)
+# Save ra, sp and s0-s11 into `current` and continue with `next`.
+    .align 2
+__switch:
+    sd ra, 0*8(a0)
+    sd sp, 1*8(a0)
+    .set n, 0
+    .rept 12
+        SAVE_SN %n
+        .set n, n+1
+    .endr
+    ld ra, 0*8(a1)
+    ld sp, 1*8(a1)
+    .set n, 0
+    .rept 12
+        LOAD_SN %n
+        .set n, n+1
+    .endr
+    ret
+
+# First switch to a task lands here with its TrapContext on top of the stack
+__task_entry:
+    mv a0, sp
+    j __restore
@@ -0,0 +1,238 @@
+//! System calls of U-mode code.
+//!
+//! The number goes in a7, arguments in a0..a2, the result comes back in a0
+//! with negative values for errors. Kernel numbers live in `0x0900_xxxx`,
+//! far from any SBI extension ID. Anything else is treated as an SBI call and
+//! only forwarded if it is on [`SBI_WHITELIST`], so U-mode can't shut the
+//! machine down or reprogram the timer.
+
+use crate::{
+    crash, loader,
+    plic::{self, Plic},
+    sbi, stack, task, trap,
+    trap::{cause, TrapContext, UserTrapHandler},
+    uirq,
+};
+use core::ops::Range;
+use riscv::register::time;
+
+pub const SYSCALL_EXIT: usize = trap::USER_EXIT;
+// 0x0900_0001 is taken by `tests::regs`
+pub const SYSCALL_WRITE: usize = 0x0900_0010;
+pub const SYSCALL_READ: usize = 0x0900_0011;
+pub const SYSCALL_YIELD: usize = 0x0900_0012;
+pub const SYSCALL_GET_TIME: usize = 0x0900_0013;
+pub const SYSCALL_UINTR_REGISTER: usize = 0x0900_0020;
+pub const SYSCALL_IRQ_CLAIM: usize = 0x0900_0021;
+pub const SYSCALL_IRQ_COMPLETE: usize = 0x0900_0022;
+
+const EBADF: isize = -9;
+const EFAULT: isize = -14;
+const EINVAL: isize = -22;
+const ENOSYS: isize = -38;
+
+const STDIN: usize = 0;
+const STDOUT: usize = 1;
+
+/// `SBI_ERR_NOT_SUPPORTED`
+const SBI_NOT_SUPPORTED: isize = -2;
+
+/// `(eid, fid)` pairs U-mode may still call, `None` matches every fid
+const SBI_WHITELIST: &[(usize, Option<usize>)] = &[
+    // Legacy console_putchar / console_getchar
+    (0x01, None),
+    (0x02, None),
+    // Base extension, read-only queries
+    (0x10, None),
+];
+
+/// Largest interrupt source number of the PLIC
+const MAX_IRQ: usize = 1023;
+
+fn text() -> Range<usize> {
+    extern "C" {
+        fn stext();
+        fn etext();
+    }
+    stext as usize..etext as usize
+}
+
+/// Kernel `.text` and `.rodata`: U-mode routines linked into the kernel
+/// keep their literals there. Nothing else of the kernel is exposed.
+fn readable() -> Range<usize> {
+    extern "C" {
+        fn stext();
+        fn erodata();
+    }
+    stext as usize..erodata as usize
+}
+
+/// Stack of the running task, or the one `run_user` sessions use
+fn user_stack() -> Range<usize> {
+    match task::current() {
+        Some(id) => task::USER_STACKS[id].range(),
+        None => stack::USER_STACK.range(),
+    }
+}
+
+/// `buf..buf + len` has to be inside the user stack or a segment of the
+/// resident program, writable ones only if `write`. Reads may also come from
+/// [`readable`] kernel memory, writes never go to the kernel.
+fn check_buffer(buf: usize, len: usize, write: bool) -> Result<(), isize> {
+    let end = buf.checked_add(len).ok_or(EFAULT)?;
+    let inside = |range: Range<usize>| buf >= range.start && end <= range.end;
+    let in_app = || {
+        loader::segments()
+            .filter(|s| !write || s.writable())
+            .any(|s| s.contains(&(buf..end)))
+    };
+    if (!write && inside(readable())) || inside(user_stack()) || in_app() {
+        Ok(())
+    } else {
+        Err(EFAULT)
+    }
+}
+
+fn sys_write(fd: usize, buf: usize, len: usize) -> Result<usize, isize> {
+    if fd != STDOUT {
+        return Err(EBADF);
+    }
+    check_buffer(buf, len, false)?;
+    let bytes = unsafe { core::slice::from_raw_parts(buf as *const u8, len) };
+    bytes.iter().for_each(|&b| sbi::console_putchar(b as usize));
+    Ok(len)
+}
+
+/// Does not block, returns how many bytes were waiting
+fn sys_read(fd: usize, buf: usize, len: usize) -> Result<usize, isize> {
+    if fd != STDIN {
+        return Err(EBADF);
+    }
+    check_buffer(buf, len, true)?;
+    let bytes = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, len) };
+    for (n, b) in bytes.iter_mut().enumerate() {
+        match sbi::console_getchar() as isize {
+            -1 => return Ok(n),
+            c => *b = c as u8,
+        }
+    }
+    Ok(len)
+}
+
+/// A no-op outside of `task::run`
+fn sys_yield() -> Result<usize, isize> {
+    if task::current().is_some() {
+        task::yield_current();
+    }
+    Ok(0)
+}
+
+/// Ends the task, or the `run_user` session when there is none
+fn sys_exit(code: usize) -> ! {
+    crash::finish_user_fatal();
+    if task::current().is_some() {
+        task::exit_current(code)
+    }
+    trap::leave_user(code)
+}
+
+/// In `time` ticks, see `CLOCK_FREQ`
+fn sys_get_time() -> Result<usize, isize> {
+    Ok(time::read())
+}
+
+/// Kernel text or text of the resident program, instructions are at least
+/// 2-byte aligned
+fn is_code(addr: usize) -> bool {
+    let in_app = || {
+        loader::segments()
+            .filter(|s| s.executable())
+            .any(|s| s.contains(&(addr..addr.saturating_add(2))))
+    };
+    addr % 2 == 0 && (text().contains(&addr) || in_app())
+}
+
+/// Install `handler` (0 to remove it) for a user interrupt, used by
+/// `user_trap_handler`.
+fn sys_uintr_register(ucause: usize, handler: usize) -> Result<usize, isize> {
+    match ucause {
+        cause::USER_SOFT | cause::USER_TIMER | cause::USER_EXTERNAL => {}
+        _ => return Err(EINVAL),
+    }
+    let handler = match handler {
+        0 => None,
+        addr if is_code(addr) => {
+            Some(unsafe { core::mem::transmute::<usize, UserTrapHandler>(addr) })
+        }
+        _ => return Err(EFAULT),
+    };
+    trap::set_user_handler(ucause, handler);
+    Ok(0)
+}
+
+/// IRQs queued for the task first, then the U-mode PLIC context. 0 if
+/// nothing is pending.
+fn sys_irq_claim() -> Result<usize, isize> {
+    if let Some(irq) = task::current().and_then(uirq::claim) {
+        return Ok(irq as usize);
+    }
+    Ok(Plic::claim(plic::get_context(0, 'U')).map_or(0, |irq| irq as usize))
+}
+
+fn sys_irq_complete(irq: usize) -> Result<usize, isize> {
+    if irq == 0 || irq > MAX_IRQ {
+        return Err(EINVAL);
+    }
+    let irq = irq as u16;
+    if let Some(task) = task::current() {
+        if uirq::complete(task, irq) {
+            return Ok(0);
+        }
+    }
+    Plic::complete(plic::get_context(0, 'U'), irq);
+    Ok(0)
+}
+
+/// Forward a whitelisted SBI call, refuse the rest.
+fn sbi_passthrough(cx: &mut TrapContext) {
+    let (eid, fid) = (cx.x[17], cx.x[16]);
+    let allowed = SBI_WHITELIST
+        .iter()
+        .any(|&(e, f)| e == eid && f.map_or(true, |f| f == fid));
+    if !allowed {
+        warn!(
+            "[SYSCALL] refused SBI call {:#x}/{:#x} from U-mode",
+            eid, fid
+        );
+        cx.x[10] = SBI_NOT_SUPPORTED as usize;
+        return;
+    }
+    let ret = sbi::sbi_call_ext(eid, fid, cx.x[10], cx.x[11], cx.x[12]);
+    cx.x[10] = ret.error as usize;
+    // Legacy calls only return a0
+    if eid >= 0x10 {
+        cx.x[11] = ret.value;
+    }
+}
+
+/// `USER_ENV_CALL` handler
+pub fn handle(cx: &mut TrapContext, _scause: usize) {
+    cx.sepc += 4;
+    let [a0, a1, a2] = [cx.x[10], cx.x[11], cx.x[12]];
+    let ret = match cx.x[17] {
+        SYSCALL_EXIT => sys_exit(a0),
+        SYSCALL_WRITE => sys_write(a0, a1, a2),
+        SYSCALL_READ => sys_read(a0, a1, a2),
+        SYSCALL_YIELD => sys_yield(),
+        SYSCALL_GET_TIME => sys_get_time(),
+        SYSCALL_UINTR_REGISTER => sys_uintr_register(a0, a1),
+        SYSCALL_IRQ_CLAIM => sys_irq_claim(),
+        SYSCALL_IRQ_COMPLETE => sys_irq_complete(a0),
+        id if id & !0xffff == 0x0900_0000 => Err(ENOSYS),
+        _ => return sbi_passthrough(cx),
+    };
+    cx.x[10] = match ret {
+        Ok(value) => value,
+        Err(errno) => errno as usize,
+    };
+}
@@ -0,0 +1,231 @@
+//! U-mode tasks with their own stacks and a round-robin scheduler.
+//!
+//! [`run`] turns the calling S-mode context into the idle loop: it switches
+//! to the next ready task and only gets control back when that task yields,
+//! is preempted by the S-mode timer after [`TIME_SLICE`] ticks, or exits.
+//! [`run`] returns once every task has exited. The user-level interrupt CSRs
+//! are part of the task and swapped by the idle loop.
+
+use crate::{
+    sbi::set_timer,
+    stack::{KernelStack, UserStack},
+    trap::{self, cause, TrapContext},
+    uintr::{self, UserState},
+    uirq, CLOCK_FREQ,
+};
+use riscv::register::{sie, sstatus, time};
+
+pub const MAX_TASKS: usize = 4;
+pub const TIME_SLICE: usize = CLOCK_FREQ / 100;
+
+const SPP: usize = 1 << 8;
+const SPIE: usize = 1 << 5;
+
+/// Callee-saved state of the S-mode side, see switch.asm
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TaskContext {
+    ra: usize,
+    sp: usize,
+    s: [usize; 12],
+}
+
+impl TaskContext {
+    const fn zero() -> Self {
+        Self {
+            ra: 0,
+            sp: 0,
+            s: [0; 12],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskStatus {
+    Free,
+    Ready,
+    Running,
+    Exited,
+}
+
+#[derive(Clone, Copy)]
+struct Task {
+    status: TaskStatus,
+    cx: TaskContext,
+    user: UserState,
+    exit_code: usize,
+}
+
+const NO_USER_STATE: UserState = UserState {
+    ustatus: 0,
+    uie: 0,
+    uip: 0,
+    utvec: 0,
+    uscratch: 0,
+    uepc: 0,
+    ucause: 0,
+    utval: 0,
+};
+
+const FREE: Task = Task {
+    status: TaskStatus::Free,
+    cx: TaskContext::zero(),
+    user: NO_USER_STATE,
+    exit_code: 0,
+};
+
+const KERNEL_STACK: KernelStack = KernelStack::new();
+const USER_STACK: UserStack = UserStack::new();
+
+pub static KERNEL_STACKS: [KernelStack; MAX_TASKS] = [KERNEL_STACK; MAX_TASKS];
+pub static USER_STACKS: [UserStack; MAX_TASKS] = [USER_STACK; MAX_TASKS];
+
+static mut TASKS: [Task; MAX_TASKS] = [FREE; MAX_TASKS];
+static mut CURRENT: Option<usize> = None;
+static mut IDLE: TaskContext = TaskContext::zero();
+/// Times a task was taken off the CPU before it exited
+static mut SWITCHES: usize = 0;
+
+global_asm!(include_str!("switch.asm"));
+
+extern "C" {
+    fn __switch(current: *mut TaskContext, next: *const TaskContext);
+    fn __task_entry();
+}
+
+/// Signature of a task body, it runs in U-mode
+pub type TaskFn = extern "C" fn(usize) -> usize;
+
+/// First U-mode code of a task
+extern "C" fn task_start(f: TaskFn, arg: usize) -> ! {
+    trap::exit_user(f(arg))
+}
+
+/// Prepare `f(arg)` as a new task, `None` if every slot is taken.
+pub fn spawn(f: TaskFn, arg: usize) -> Option<usize> {
+    let id = unsafe { TASKS.iter().position(|t| t.status == TaskStatus::Free)? };
+    let mut cx = TrapContext {
+        x: [0; 32],
+        sstatus: sstatus::read().bits() & !SPP | SPIE,
+        sepc: task_start as usize,
+    };
+    cx.x[2] = USER_STACKS[id].get_sp();
+    cx.x[10] = f as usize;
+    cx.x[11] = arg;
+    let cx = KERNEL_STACKS[id].push_context(cx);
+    let mut task_cx = TaskContext::zero();
+    task_cx.ra = __task_entry as usize;
+    task_cx.sp = cx as *mut _ as usize;
+    unsafe {
+        TASKS[id] = Task {
+            status: TaskStatus::Ready,
+            cx: task_cx,
+            user: NO_USER_STATE,
+            exit_code: 0,
+        };
+    }
+    Some(id)
+}
+
+/// Where a descheduled task resumes from: its outermost trap from U-mode is
+/// always at the top of its kernel stack.
+fn trap_context(id: usize) -> &'static mut TrapContext {
+    let addr = KERNEL_STACKS[id].get_sp() - core::mem::size_of::<TrapContext>();
+    unsafe { &mut *(addr as *mut TrapContext) }
+}
+
+/// Id of the task running right now, `None` outside of [`run`]
+pub fn current() -> Option<usize> {
+    unsafe { CURRENT }
+}
+
+/// Give the CPU back to the idle loop, the task stays ready.
+pub fn yield_current() {
+    let id = unsafe { CURRENT.take() }.expect("yield outside of a task");
+    unsafe {
+        TASKS[id].status = TaskStatus::Ready;
+        SWITCHES += 1;
+        __switch(&mut TASKS[id].cx, &IDLE);
+    }
+}
+
+pub fn exit_current(code: usize) -> ! {
+    let id = unsafe { CURRENT.take() }.expect("exit outside of a task");
+    let mut dead = TaskContext::zero();
+    unsafe {
+        TASKS[id].status = TaskStatus::Exited;
+        TASKS[id].exit_code = code;
+        __switch(&mut dead, &IDLE);
+    }
+    unreachable!("exited task scheduled again")
+}
+
+fn preempt(_cx: &mut TrapContext, _scause: usize) {
+    set_timer(time::read() + TIME_SLICE);
+    if current().is_some() {
+        yield_current();
+    }
+}
+
+/// Next ready task after `last`, round robin
+fn pick_next(last: usize) -> Option<usize> {
+    (1..=MAX_TASKS)
+        .map(|i| (last + i) % MAX_TASKS)
+        .find(|&i| unsafe { TASKS[i].status } == TaskStatus::Ready)
+}
+
+/// Exit codes of the spawned tasks and how many times they were switched out
+pub struct Report {
+    pub exit_codes: [Option<usize>; MAX_TASKS],
+    pub switches: usize,
+}
+
+/// Schedule the spawned tasks until all of them exited.
+pub fn run() -> Report {
+    let previous = trap::register(cause::SUPERVISOR_TIMER, preempt);
+    unsafe {
+        SWITCHES = 0;
+        sie::set_stimer();
+    }
+    set_timer(time::read() + TIME_SLICE);
+
+    let backend = uintr::backend();
+    let outside = backend.map(|b| b.save_state());
+    let mut last = MAX_TASKS - 1;
+    while let Some(id) = pick_next(last) {
+        last = id;
+        unsafe {
+            if let Some(backend) = backend {
+                uirq::deliver_to(id, &mut TASKS[id].user, trap_context(id));
+                backend.load_state(&TASKS[id].user);
+            }
+            TASKS[id].status = TaskStatus::Running;
+            CURRENT = Some(id);
+            __switch(&mut IDLE, &TASKS[id].cx);
+            if let Some(backend) = backend {
+                TASKS[id].user = backend.save_state();
+            }
+        }
+    }
+    if let (Some(backend), Some(state)) = (backend, outside) {
+        backend.load_state(&state);
+    }
+
+    set_timer(usize::MAX);
+    unsafe {
+        sie::clear_stimer();
+    }
+    trap::set_handler(cause::SUPERVISOR_TIMER, previous);
+
+    let mut exit_codes = [None; MAX_TASKS];
+    for (task, code) in unsafe { TASKS.iter_mut() }.zip(exit_codes.iter_mut()) {
+        if task.status == TaskStatus::Exited {
+            *code = Some(task.exit_code);
+        }
+        *task = FREE;
+    }
+    Report {
+        exit_codes,
+        switches: unsafe { SWITCHES },
+    }
+}
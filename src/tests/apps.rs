@@ -0,0 +1,29 @@
+//! Run every user program embedded by `build.rs`, each must exit with 0.
+
+use super::Outcome;
+use crate::{loader, uintr};
+
+pub fn run() -> Outcome {
+    if loader::APPS.is_empty() {
+        info!("[APPS] no user programs embedded");
+        return Outcome::Skip;
+    }
+    if let Some(backend) = uintr::backend() {
+        backend.delegate(uintr::SOFT);
+    }
+    let mut ok = true;
+    for app in loader::APPS {
+        match loader::run(app) {
+            Ok(0) => info!("[APPS] {} PASS", app.name),
+            Ok(code) => {
+                error!("[APPS] {} exited with {:#x}", app.name, code);
+                ok = false;
+            }
+            Err(err) => {
+                error!("[APPS] {} failed to load: {:?}", app.name, err);
+                ok = false;
+            }
+        }
+    }
+    ok.into()
+}
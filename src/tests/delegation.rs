@@ -0,0 +1,379 @@
+//! `sideleg`/`sedeleg` delegation matrix.
+//!
+//! Every source is armed twice from a small U-mode snippet, once with its
+//! delegation bit clear and once with it set, and we record whether the trap
+//! lands in `trap_handler` (S) or `user_trap_handler` (U).
+
+use super::{arm_uart_irq, disarm_uart_irq, Outcome};
+use crate::{
+    stack, trap,
+    trap::{cause, TrapContext, UserTrapContext},
+};
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering::SeqCst};
+use riscv::register::sstatus;
+/// `scause`/`ucause` of the armed source
+static ARMED: AtomicUsize = AtomicUsize::new(0);
+static OBSERVED: AtomicU8 = AtomicU8::new(Handler::None as u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Handler {
+    None,
+    Supervisor,
+    User,
+}
+
+impl Handler {
+    fn from_u8(v: u8) -> Self {
+        match v {
+            1 => Handler::Supervisor,
+            2 => Handler::User,
+            _ => Handler::None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Handler::None => "-",
+            Handler::Supervisor => "S",
+            Handler::User => "U",
+        }
+    }
+}
+
+struct Source {
+    name: &'static str,
+    /// Exception or interrupt code, also the bit in `sedeleg`/`sideleg`
+    code: usize,
+    interrupt: bool,
+    /// U-mode snippet, `None` if S-mode has no way to raise it
+    entry: Option<unsafe extern "C" fn()>,
+    arm: fn(),
+    /// Called from whichever handler takes the trap
+    disarm: fn(user: bool),
+    /// The hardware may legally handle it without trapping
+    optional: bool,
+}
+
+impl Source {
+    fn cause(&self) -> usize {
+        self.code | if self.interrupt { cause::INTERRUPT } else { 0 }
+    }
+}
+
+// Each snippet raises its source and then leaves through an ecall (or an
+// ebreak for the ecall row) with a7 = trap::USER_EXIT. Exceptions are resumed
+// at epc + 4, so nothing here may be compressed.
+global_asm!(
+    "
+    .section .text
+    .option push
+    .option norvc
+    .globl __deleg_illegal
+    .align 2
+__deleg_illegal:
+    li a7, 0
+    csrr t0, sstatus
+    li a7, 0x09000000
+    ecall
+    .globl __deleg_breakpoint
+    .align 2
+__deleg_breakpoint:
+    li a7, 0
+    ebreak
+    li a7, 0x09000000
+    ecall
+    .globl __deleg_load_misaligned
+    .align 2
+__deleg_load_misaligned:
+    li a7, 0
+    ld t0, -15(sp)
+    li a7, 0x09000000
+    ecall
+    .globl __deleg_load_fault
+    .align 2
+__deleg_load_fault:
+    li a7, 0
+    ld t0, 0(zero)
+    li a7, 0x09000000
+    ecall
+    .globl __deleg_store_misaligned
+    .align 2
+__deleg_store_misaligned:
+    li a7, 0
+    sd zero, -15(sp)
+    li a7, 0x09000000
+    ecall
+    .globl __deleg_store_fault
+    .align 2
+__deleg_store_fault:
+    li a7, 0
+    sd zero, 0(zero)
+    li a7, 0x09000000
+    ecall
+    .globl __deleg_ecall
+    .align 2
+__deleg_ecall:
+    li a7, 0
+    ecall
+    li a7, 0x09000000
+    ebreak
+    .globl __deleg_wait
+    .align 2
+__deleg_wait:
+    li a7, 0
+    li t0, 100000
+1:
+    addi t0, t0, -1
+    bnez t0, 1b
+    li a7, 0x09000000
+    ecall
+    .option pop
+"
+);
+
+extern "C" {
+    fn __deleg_illegal();
+    fn __deleg_breakpoint();
+    fn __deleg_load_misaligned();
+    fn __deleg_load_fault();
+    fn __deleg_store_misaligned();
+    fn __deleg_store_fault();
+    fn __deleg_ecall();
+    fn __deleg_wait();
+}
+
+fn nothing() {}
+
+fn nothing_in(_user: bool) {}
+
+fn arm_usoft() {
+    unsafe {
+        set_csr!(0x104, 1 << 0); // sie.USIE
+        set_csr!(0x004, 1 << 0); // uie.USIE
+        set_csr!(0x144, 1 << 0); // sip.USIP
+    }
+}
+
+fn disarm_usoft(user: bool) {
+    unsafe {
+        if user {
+            clear_csr!(0x044, 1 << 0); // uip.USIP
+        } else {
+            clear_csr!(0x144, 1 << 0); // sip.USIP
+        }
+    }
+}
+
+fn arm_uext() {
+    unsafe {
+        set_csr!(0x104, 1 << 8); // sie.UEIE
+        set_csr!(0x004, 1 << 8); // uie.UEIE
+    }
+    arm_uart_irq('U');
+}
+
+fn disarm_uext(_user: bool) {
+    disarm_uart_irq('U');
+}
+
+macro_rules! exception {
+    ($name: literal, $code: literal, $entry: ident, $optional: literal) => {
+        Source {
+            name: $name,
+            code: $code,
+            interrupt: false,
+            entry: Some($entry),
+            arm: nothing,
+            disarm: nothing_in,
+            optional: $optional,
+        }
+    };
+}
+
+static SOURCES: &[Source] = &[
+    Source {
+        name: "UserSoft",
+        code: 0,
+        interrupt: true,
+        entry: Some(__deleg_wait),
+        arm: arm_usoft,
+        disarm: disarm_usoft,
+        optional: false,
+    },
+    Source {
+        // UTIP can only be raised from M-mode
+        name: "UserTimer",
+        code: 4,
+        interrupt: true,
+        entry: None,
+        arm: nothing,
+        disarm: nothing_in,
+        optional: true,
+    },
+    Source {
+        name: "UserExternal",
+        code: 8,
+        interrupt: true,
+        entry: Some(__deleg_wait),
+        arm: arm_uext,
+        disarm: disarm_uext,
+        optional: false,
+    },
+    exception!("IllegalInstruction", 2, __deleg_illegal, false),
+    exception!("Breakpoint", 3, __deleg_breakpoint, false),
+    exception!("LoadMisaligned", 4, __deleg_load_misaligned, true),
+    exception!("LoadFault", 5, __deleg_load_fault, false),
+    exception!("StoreMisaligned", 6, __deleg_store_misaligned, true),
+    exception!("StoreFault", 7, __deleg_store_fault, false),
+    exception!("UserEnvCall", 8, __deleg_ecall, false),
+];
+
+/// The emulation takes user interrupts only on a trap back to U-mode, which
+/// the wait loop never does, and owns the illegal-instruction trap that row
+/// would replace.
+#[cfg(feature = "n_emul")]
+fn beyond_emulation(source: &Source) -> bool {
+    crate::emul::enabled() && (source.interrupt || source.code == cause::ILLEGAL_INSTRUCTION)
+}
+
+#[cfg(not(feature = "n_emul"))]
+fn beyond_emulation(_source: &Source) -> bool {
+    false
+}
+
+fn armed_source() -> &'static Source {
+    let cause = ARMED.load(SeqCst);
+    SOURCES.iter().find(|s| s.cause() == cause).unwrap()
+}
+
+fn on_supervisor_trap(cx: &mut TrapContext, _scause: usize) {
+    OBSERVED.store(Handler::Supervisor as u8, SeqCst);
+    let source = armed_source();
+    if source.interrupt {
+        (source.disarm)(false);
+    } else {
+        cx.sepc += 4;
+    }
+}
+
+/// Runs in U-mode
+fn on_user_trap(cx: &mut UserTrapContext, _ucause: usize) {
+    OBSERVED.store(Handler::User as u8, SeqCst);
+    let source = armed_source();
+    if source.interrupt {
+        (source.disarm)(true);
+    } else {
+        cx.uepc += 4;
+    }
+}
+
+/// The ecall row replaces the ecall handler, so it leaves through an ebreak
+fn exit_on_breakpoint(cx: &mut TrapContext, _scause: usize) {
+    if cx.x[17] == trap::USER_EXIT {
+        trap::leave_user(0);
+    }
+    warn!("[DELEG] stray ebreak at {:#x}", cx.sepc);
+    // Step over it, `c.ebreak` is 2 bytes
+    let low = unsafe { (cx.sepc as *const u16).read() };
+    cx.sepc += if low & 0b11 == 0b11 { 4 } else { 2 };
+}
+
+fn observe(source: &Source, entry: unsafe extern "C" fn(), delegate: bool) -> Handler {
+    let cause = source.cause();
+    let bit = 1 << source.code;
+    unsafe {
+        if delegate {
+            if source.interrupt {
+                set_csr!(0x103, bit); // sideleg
+            } else {
+                set_csr!(0x102, bit); // sedeleg
+            }
+        }
+    }
+    let previous = trap::register(cause, on_supervisor_trap);
+    let previous_user = trap::register_user(cause, on_user_trap);
+    let previous_exit = (cause == cause::USER_ENV_CALL)
+        .then(|| trap::register(cause::BREAKPOINT, exit_on_breakpoint));
+    OBSERVED.store(Handler::None as u8, SeqCst);
+    ARMED.store(cause, SeqCst);
+    (source.arm)();
+    trap::run_user(entry as usize, stack::USER_STACK.get_sp());
+    (source.disarm)(false);
+    if let Some(previous_exit) = previous_exit {
+        trap::set_handler(cause::BREAKPOINT, previous_exit);
+    }
+    trap::set_handler(cause, previous);
+    trap::set_user_handler(cause, previous_user);
+    unsafe {
+        clear_csr!(0x103, bit);
+        clear_csr!(0x102, bit);
+    }
+    Handler::from_u8(OBSERVED.load(SeqCst))
+}
+
+/// Print the cause × delegation → handler table, passes if every
+/// source went where its delegation bit says.
+pub fn run() -> Outcome {
+    let saved = unsafe {
+        [
+            read_csr!(0x000), // ustatus
+            read_csr!(0x004), // uie
+            read_csr!(0x005), // utvec
+            read_csr!(0x102), // sedeleg
+            read_csr!(0x103), // sideleg
+            read_csr!(0x104), // sie
+        ]
+    };
+    unsafe {
+        sstatus::clear_sie();
+        write_csr!(0x102, 0);
+        write_csr!(0x103, 0);
+        set_csr!(0x000, 1 << 0); // ustatus.UIE
+    }
+    trap::init_u();
+
+    info!("[DELEG] {:<20} {:>8} {:>8}", "cause", "deleg=0", "deleg=1");
+    let mut ok = true;
+    for source in SOURCES {
+        let entry = match source.entry {
+            Some(entry) if !beyond_emulation(source) => entry,
+            _ => {
+                info!("[DELEG] {:<20} {:>8} {:>8}", source.name, "n/a", "n/a");
+                continue;
+            }
+        };
+        let direct = observe(source, entry, false);
+        let delegated = observe(source, entry, true);
+        let expect = |observed, expected| {
+            observed == expected || (observed == Handler::None && source.optional)
+        };
+        let pass = expect(direct, Handler::Supervisor) && expect(delegated, Handler::User);
+        ok &= pass;
+        if pass {
+            info!(
+                "[DELEG] {:<20} {:>8} {:>8}",
+                source.name,
+                direct.as_str(),
+                delegated.as_str()
+            );
+        } else {
+            error!(
+                "[DELEG] {:<20} {:>8} {:>8}  <- unexpected",
+                source.name,
+                direct.as_str(),
+                delegated.as_str()
+            );
+        }
+    }
+
+    unsafe {
+        write_csr!(0x000, saved[0]);
+        write_csr!(0x004, saved[1]);
+        write_csr!(0x005, saved[2]);
+        write_csr!(0x102, saved[3]);
+        write_csr!(0x103, saved[4]);
+        write_csr!(0x104, saved[5]);
+    }
+    ok.into()
+}
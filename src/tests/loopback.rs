@@ -0,0 +1,188 @@
+//! Data integrity between `uart1` and `uart2`, which are wired to each other.
+//!
+//! Both send [`FRAMES`] numbered, checksummed frames to the other one at the
+//! same time through the polling driver. The receiving side checks every
+//! frame and counts what went missing, arrived twice or got mangled.
+
+use super::Outcome;
+use crate::{
+    user_uart::{get_base_addr_from_irq, Blocking, PollingSerial},
+    BAUD_RATE, CLOCK_FREQ,
+};
+use embedded_hal::serial::{Read, Write};
+use riscv::register::time;
+
+const FRAMES: u16 = 2048;
+/// `SYNC`, sequence number (little endian), payload, checksum
+const FRAME_LEN: usize = 8;
+const PAYLOAD_LEN: usize = FRAME_LEN - 4;
+const SYNC: u8 = 0xa5;
+/// Time to let the tail of the traffic arrive
+const TIMEOUT: usize = CLOCK_FREQ * 2;
+/// Rx silence, far more than a few character times, after which leftovers
+/// of earlier tests are assumed gone
+const QUIET: usize = CLOCK_FREQ / 1000;
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |sum: u8, b| sum.rotate_left(1) ^ b)
+}
+
+fn frame(seq: u16) -> [u8; FRAME_LEN] {
+    let mut frame = [0; FRAME_LEN];
+    frame[0] = SYNC;
+    frame[1..3].copy_from_slice(&seq.to_le_bytes());
+    for (i, b) in frame[3..3 + PAYLOAD_LEN].iter_mut().enumerate() {
+        *b = (seq as u8).wrapping_mul(31).wrapping_add(i as u8);
+    }
+    frame[FRAME_LEN - 1] = checksum(&frame[1..FRAME_LEN - 1]);
+    frame
+}
+
+/// Receiving side of one direction, all counts are in bytes. A mangled frame
+/// is counted as corrupted only, not as dropped as well.
+#[derive(Default)]
+struct Checker {
+    window: [u8; FRAME_LEN],
+    len: usize,
+    /// Next sequence number expected
+    next: u16,
+    received: usize,
+    dropped: usize,
+    duplicated: usize,
+    corrupted: usize,
+    /// Corrupted since the last accepted frame, they stand in for some of
+    /// the frames that seem dropped
+    pending_corrupt: usize,
+}
+
+impl Checker {
+    fn push(&mut self, byte: u8) {
+        if self.len == 0 && byte != SYNC {
+            self.corrupt(1);
+            return;
+        }
+        self.window[self.len] = byte;
+        self.len += 1;
+        if self.len < FRAME_LEN {
+            return;
+        }
+        let seq = u16::from_le_bytes([self.window[1], self.window[2]]);
+        if self.window == frame(seq) {
+            self.accept(seq);
+            self.len = 0;
+        } else {
+            // Drop the bogus sync byte and resync on the next one
+            let resync = self.window[1..].iter().position(|&b| b == SYNC);
+            let skip = resync.map_or(FRAME_LEN, |i| i + 1);
+            self.corrupt(skip);
+            self.window.copy_within(skip.., 0);
+            self.len = FRAME_LEN - skip;
+        }
+    }
+
+    fn accept(&mut self, seq: u16) {
+        let ahead = seq.wrapping_sub(self.next) as i16;
+        if ahead < 0 {
+            self.duplicated += FRAME_LEN;
+            return;
+        }
+        self.missing(ahead as usize * FRAME_LEN);
+        self.received += FRAME_LEN;
+        self.next = seq.wrapping_add(1);
+    }
+
+    fn corrupt(&mut self, bytes: usize) {
+        self.corrupted += bytes;
+        self.pending_corrupt += bytes;
+    }
+
+    /// `gap` bytes of frames were skipped, minus what arrived corrupted
+    fn missing(&mut self, gap: usize) {
+        self.dropped += gap - self.pending_corrupt.min(gap);
+        self.pending_corrupt = 0;
+    }
+
+    fn complete(&self) -> bool {
+        self.next == FRAMES
+    }
+
+    /// Count the frames that never arrived as dropped.
+    fn finish(&mut self) {
+        self.corrupt(self.len);
+        self.missing(FRAMES.wrapping_sub(self.next) as usize * FRAME_LEN);
+        self.len = 0;
+        self.next = FRAMES;
+    }
+
+    fn clean(&self) -> bool {
+        self.dropped == 0 && self.duplicated == 0 && self.corrupted == 0
+    }
+}
+
+fn drain(serial: &mut PollingSerial, checker: &mut Checker) {
+    while let Ok(byte) = serial.try_read() {
+        checker.push(byte);
+    }
+}
+
+/// Throw away whatever earlier tests left in flight, until neither serial
+/// received anything for [`QUIET`] ticks. Returns the bytes discarded.
+fn settle(uart1: &mut PollingSerial, uart2: &mut PollingSerial) -> usize {
+    let deadline = time::read() + TIMEOUT;
+    let mut last = time::read();
+    let mut discarded = 0;
+    while time::read() - last < QUIET && time::read() < deadline {
+        while uart1.try_read().is_ok() || uart2.try_read().is_ok() {
+            discarded += 1;
+            last = time::read();
+        }
+    }
+    discarded
+}
+
+pub fn run() -> Outcome {
+    #[cfg(feature = "board_qemu")]
+    let irqs = (14, 15);
+    #[cfg(feature = "board_lrv")]
+    let irqs = (6, 7);
+    let mut uart1 = PollingSerial::new(get_base_addr_from_irq(irqs.0));
+    let mut uart2 = PollingSerial::new(get_base_addr_from_irq(irqs.1));
+    uart1.hardware_init(BAUD_RATE);
+    uart2.hardware_init(BAUD_RATE);
+    let stale = settle(&mut uart1, &mut uart2);
+    if stale != 0 {
+        info!("[LOOPBACK] discarded {} stale bytes", stale);
+    }
+
+    // `to2` checks what uart2 received from uart1, `to1` the way back
+    let (mut to2, mut to1) = (Checker::default(), Checker::default());
+    let start = time::read();
+    for seq in 0..FRAMES {
+        let frame = frame(seq);
+        let _ = Blocking(&mut uart1).write_all(&frame);
+        let _ = Blocking(&mut uart2).write_all(&frame);
+        drain(&mut uart2, &mut to2);
+        drain(&mut uart1, &mut to1);
+    }
+    while !(to2.complete() && to1.complete()) && time::read() - start < TIMEOUT {
+        drain(&mut uart2, &mut to2);
+        drain(&mut uart1, &mut to1);
+    }
+    let elapsed = time::read() - start;
+
+    let mut ok = true;
+    for (name, checker) in [("uart1 -> uart2", &mut to2), ("uart2 -> uart1", &mut to1)].iter_mut() {
+        checker.finish();
+        info!(
+            "[LOOPBACK] {}: {} bytes, {} bytes/s, dropped {}, duplicated {}, corrupted {}",
+            name,
+            checker.received,
+            checker.received * CLOCK_FREQ / elapsed.max(1),
+            checker.dropped,
+            checker.duplicated,
+            checker.corrupted
+        );
+        ok &= checker.clean();
+    }
+    ok.into()
+}
@@ -0,0 +1,280 @@
+//! In-kernel conformance tests for the N extension.
+//!
+//! Every test is a [`TestCase`] in [`TESTS`]. Which ones run is picked at build
+//! time with `TESTS=warl,uret`; without it the kernel waits a second for a list
+//! typed on the console. The trap handler registry and the trap related CSRs
+//! are saved before and restored after every test.
+
+pub mod apps;
+pub mod delegation;
+pub mod loopback;
+pub mod regs;
+pub mod sched;
+pub mod task_csrs;
+pub mod uart;
+pub mod uirq;
+pub mod uret;
+pub mod warl;
+
+use crate::{
+    csr_probe, exit, features,
+    plic::{self, Plic},
+    sbi, trap,
+    user_uart::{get_base_addr_from_irq, SerialHardware},
+    CLOCK_FREQ,
+};
+use riscv::register::time;
+use rv_plic::Priority;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    Fail,
+    Skip,
+}
+
+impl From<bool> for Outcome {
+    fn from(ok: bool) -> Self {
+        if ok {
+            Outcome::Pass
+        } else {
+            Outcome::Fail
+        }
+    }
+}
+
+/// What a test is skipped without
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Needs {
+    Nothing,
+    /// Hardware N extension or its emulation behind `n_emul`
+    UserInterrupts,
+    /// Hardware N extension, the emulation does not cover it
+    NExtension,
+}
+
+pub struct TestCase {
+    pub name: &'static str,
+    pub needs: Needs,
+    pub setup: Option<fn()>,
+    pub run: fn() -> Outcome,
+    pub teardown: Option<fn()>,
+}
+
+macro_rules! test_case {
+    ($name: literal, $needs: ident, $run: path) => {
+        TestCase {
+            name: $name,
+            needs: Needs::$needs,
+            setup: None,
+            run: $run,
+            teardown: None,
+        }
+    };
+    ($name: literal, $needs: ident, $setup: path, $run: path, $teardown: path) => {
+        TestCase {
+            name: $name,
+            needs: Needs::$needs,
+            setup: Some($setup),
+            run: $run,
+            teardown: Some($teardown),
+        }
+    };
+}
+
+pub const TESTS: &[TestCase] = &[
+    test_case!("csr_probe", Nothing, csr_probe_report),
+    test_case!("warl", NExtension, warl::run),
+    test_case!(
+        "uart_speed",
+        Nothing,
+        uart::setup,
+        uart::speed,
+        uart::teardown
+    ),
+    test_case!("uart_lite", Nothing, uart::lite),
+    test_case!("uart_nb", Nothing, uart::nb_semantics),
+    test_case!("uart_loopback", Nothing, loopback::run),
+    test_case!("uart_kernel", Nothing, uart::kernel_irq),
+    test_case!("uart_user", NExtension, uart::user_irq),
+    test_case!("delegation", UserInterrupts, delegation::run),
+    test_case!("uret", UserInterrupts, uret::run),
+    test_case!("regs", NExtension, regs::run),
+    test_case!("apps", NExtension, apps::run),
+    test_case!("sched", Nothing, sched::run),
+    test_case!("task_csrs", UserInterrupts, task_csrs::run),
+    test_case!("uirq", NExtension, uirq::run),
+];
+
+fn csr_probe_report() -> Outcome {
+    csr_probe::probe_all();
+    Outcome::Pass
+}
+
+/// CSRs a test may leave modified
+const ISOLATED_CSRS: &[&str] = &[
+    "sstatus", "sie", "sip", "sedeleg", "sideleg", "stvec", "ustatus", "uie", "uip", "utvec",
+    "uscratch",
+];
+
+struct Snapshot {
+    handlers: trap::HandlerTable,
+    csrs: [Option<usize>; ISOLATED_CSRS.len()],
+    /// `try_access` bypasses the emulation, so the emulated CSRs read as
+    /// missing above and are kept here instead
+    #[cfg(feature = "n_emul")]
+    shadow: crate::emul::UserCsrs,
+}
+
+fn csr(name: &str) -> &'static csr_probe::CsrEntry {
+    csr_probe::CSR_TABLE
+        .iter()
+        .find(|e| e.name == name)
+        .unwrap()
+}
+
+impl Snapshot {
+    fn take() -> Self {
+        let mut csrs = [None; ISOLATED_CSRS.len()];
+        for (saved, name) in csrs.iter_mut().zip(ISOLATED_CSRS) {
+            *saved = csr_probe::try_access(csr(name).read);
+        }
+        Snapshot {
+            handlers: trap::save_handlers(),
+            csrs,
+            #[cfg(feature = "n_emul")]
+            shadow: *crate::emul::current(),
+        }
+    }
+
+    fn restore(&self) {
+        for (saved, name) in self.csrs.iter().zip(ISOLATED_CSRS) {
+            if let Some(bits) = *saved {
+                let _ = csr_probe::try_access(|| (csr(name).write)(bits));
+            }
+        }
+        #[cfg(feature = "n_emul")]
+        *crate::emul::current() = self.shadow;
+        trap::restore_handlers(self.handlers);
+    }
+}
+
+const SELECTION_LEN: usize = 64;
+
+/// Give the console one second to type a selection, ended by a newline.
+fn read_selection(buf: &mut [u8; SELECTION_LEN]) -> usize {
+    println!("Tests to run (empty for default):");
+    let deadline = time::read() + CLOCK_FREQ;
+    let mut len = 0;
+    while time::read() < deadline {
+        let c = sbi::console_getchar();
+        if c > 0xff {
+            continue;
+        }
+        match c as u8 {
+            b'\r' | b'\n' => break,
+            c if len < SELECTION_LEN => {
+                buf[len] = c;
+                len += 1;
+            }
+            _ => {}
+        }
+    }
+    len
+}
+
+fn selected(name: &str, selection: &str) -> bool {
+    selection.is_empty()
+        || selection == "all"
+        || selection.split(|c| c == ',' || c == ' ').any(|s| s == name)
+}
+
+fn run_one(test: &TestCase) -> Outcome {
+    let available = match test.needs {
+        Needs::Nothing => true,
+        Needs::UserInterrupts => features::has_user_interrupts(),
+        Needs::NExtension => features::has_n_extension(),
+    };
+    if !available {
+        return Outcome::Skip;
+    }
+    let snapshot = Snapshot::take();
+    if let Some(setup) = test.setup {
+        setup();
+    }
+    let outcome = (test.run)();
+    if let Some(teardown) = test.teardown {
+        teardown();
+    }
+    snapshot.restore();
+    outcome
+}
+
+/// Run the selected tests and print a summary, failures are passed on to
+/// [`exit::record`].
+pub fn run() {
+    let mut buf = [0; SELECTION_LEN];
+    // A selection made at build time is final, only ask without one
+    let selection = match option_env!("TESTS") {
+        Some(built) => built,
+        None => {
+            let len = read_selection(&mut buf);
+            core::str::from_utf8(&buf[..len]).unwrap_or("").trim()
+        }
+    };
+
+    let mut outcomes = [Outcome::Skip; TESTS.len()];
+    for (test, outcome) in TESTS.iter().zip(outcomes.iter_mut()) {
+        if !selected(test.name, selection) {
+            continue;
+        }
+        println!("[TEST] {} ...", test.name);
+        *outcome = run_one(test);
+    }
+
+    println!("[TEST] summary:");
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for (test, outcome) in TESTS.iter().zip(outcomes.iter()) {
+        match outcome {
+            Outcome::Pass => passed += 1,
+            Outcome::Fail => failed += 1,
+            Outcome::Skip => skipped += 1,
+        }
+        println!("[TEST] {:<14} {:?}", test.name, outcome);
+    }
+    println!(
+        "[TEST] {} passed, {} failed, {} skipped",
+        passed, failed, skipped
+    );
+    exit::record(failed == 0);
+}
+
+/// UART used as an interrupt source, its THR-empty interrupt fires as soon as
+/// it is enabled.
+#[cfg(feature = "board_qemu")]
+pub const UART_IRQ: u16 = 14;
+#[cfg(feature = "board_lrv")]
+pub const UART_IRQ: u16 = 6;
+
+fn uart() -> SerialHardware {
+    SerialHardware::new(get_base_addr_from_irq(UART_IRQ))
+}
+
+/// Route `UART_IRQ` to the PLIC context of `mode` (`'S'` or `'U'`) and raise it.
+pub fn arm_uart_irq(mode: char) {
+    let ctx = plic::get_context(0, mode);
+    Plic::set_priority(UART_IRQ, Priority::lowest());
+    Plic::set_threshold(ctx, Priority::any());
+    Plic::enable(ctx, UART_IRQ);
+    uart().write_ier(0b10);
+}
+
+/// Undo [`arm_uart_irq`]. Plain MMIO, so it works from U-mode as well.
+pub fn disarm_uart_irq(mode: char) {
+    let ctx = plic::get_context(0, mode);
+    uart().write_ier(0);
+    if let Some(irq) = Plic::claim(ctx) {
+        Plic::complete(ctx, irq);
+    }
+    Plic::disable(ctx, UART_IRQ);
+}
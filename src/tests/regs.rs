@@ -0,0 +1,344 @@
+//! General-purpose register integrity across S- and U-mode traps.
+//!
+//! `__regs_stress` runs in U-mode, fills x1 and x3..x31 with distinct
+//! patterns (a7 holds `ARM`, sp its own value), and asks the kernel through an
+//! `ARM` ecall to raise one interrupt source. The interrupt is taken on the
+//! instruction right after the ecall returns, then every register is stored
+//! to a buffer on the user stack and checked from S-mode.
+//!
+//! `trap.asm` does not save tp (x4), so it is only reported, not failed.
+
+use super::{arm_uart_irq, disarm_uart_irq, Outcome};
+use crate::{
+    sbi::set_timer,
+    stack, trap,
+    trap::{cause, TrapContext, UserTrapContext},
+};
+use core::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+use riscv::register::sstatus;
+/// `a7` of the ecall that raises the armed source
+const ARM: usize = 0x0900_0001;
+const PATTERN: usize = 0xa5a5_a5a5_0000_0000;
+
+const SSIP: usize = 1 << 1;
+const STIP: usize = 1 << 5;
+const SEIP: usize = 1 << 9;
+const USIP: usize = 1 << 0;
+const UEIP: usize = 1 << 8;
+
+static SOURCE: AtomicUsize = AtomicUsize::new(0);
+/// `scause`/`ucause` of the interrupt actually taken, `usize::MAX` if none
+static TAKEN: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+global_asm!(
+    "
+    .section .text
+    .option push
+    .option norvc
+    .globl __regs_stress
+    .align 2
+__regs_stress:
+    addi sp, sp, -32*8
+    li x1, 0xa5a5a5a500000101
+    li x3, 0xa5a5a5a500000303
+    li x4, 0xa5a5a5a500000404
+    li x5, 0xa5a5a5a500000505
+    li x6, 0xa5a5a5a500000606
+    li x7, 0xa5a5a5a500000707
+    li x8, 0xa5a5a5a500000808
+    li x9, 0xa5a5a5a500000909
+    li x10, 0xa5a5a5a500000a0a
+    li x11, 0xa5a5a5a500000b0b
+    li x12, 0xa5a5a5a500000c0c
+    li x13, 0xa5a5a5a500000d0d
+    li x14, 0xa5a5a5a500000e0e
+    li x15, 0xa5a5a5a500000f0f
+    li x16, 0xa5a5a5a500001010
+    li x18, 0xa5a5a5a500001212
+    li x19, 0xa5a5a5a500001313
+    li x20, 0xa5a5a5a500001414
+    li x21, 0xa5a5a5a500001515
+    li x22, 0xa5a5a5a500001616
+    li x23, 0xa5a5a5a500001717
+    li x24, 0xa5a5a5a500001818
+    li x25, 0xa5a5a5a500001919
+    li x26, 0xa5a5a5a500001a1a
+    li x27, 0xa5a5a5a500001b1b
+    li x28, 0xa5a5a5a500001c1c
+    li x29, 0xa5a5a5a500001d1d
+    li x30, 0xa5a5a5a500001e1e
+    li x31, 0xa5a5a5a500001f1f
+    li a7, 0x09000001
+    ecall
+    .rept 16
+    nop
+    .endr
+    sd x1, 1*8(sp)
+    sd x2, 2*8(sp)
+    sd x3, 3*8(sp)
+    sd x4, 4*8(sp)
+    sd x5, 5*8(sp)
+    sd x6, 6*8(sp)
+    sd x7, 7*8(sp)
+    sd x8, 8*8(sp)
+    sd x9, 9*8(sp)
+    sd x10, 10*8(sp)
+    sd x11, 11*8(sp)
+    sd x12, 12*8(sp)
+    sd x13, 13*8(sp)
+    sd x14, 14*8(sp)
+    sd x15, 15*8(sp)
+    sd x16, 16*8(sp)
+    sd x17, 17*8(sp)
+    sd x18, 18*8(sp)
+    sd x19, 19*8(sp)
+    sd x20, 20*8(sp)
+    sd x21, 21*8(sp)
+    sd x22, 22*8(sp)
+    sd x23, 23*8(sp)
+    sd x24, 24*8(sp)
+    sd x25, 25*8(sp)
+    sd x26, 26*8(sp)
+    sd x27, 27*8(sp)
+    sd x28, 28*8(sp)
+    sd x29, 29*8(sp)
+    sd x30, 30*8(sp)
+    sd x31, 31*8(sp)
+    mv a0, sp
+    li a7, 0x09000000
+    ecall
+    .option pop
+"
+);
+
+extern "C" {
+    fn __regs_stress();
+}
+
+struct Source {
+    name: &'static str,
+    /// One of the interrupt causes in `trap::cause`
+    cause: usize,
+    /// Taken by `user_trap_handler` instead of `trap_handler`
+    user: bool,
+    arm: fn(),
+}
+
+fn arm_stimer() {
+    unsafe {
+        set_csr!(0x104, STIP); // sie.STIE
+    }
+    set_timer(0);
+}
+
+fn arm_ssoft() {
+    unsafe {
+        set_csr!(0x104, SSIP); // sie.SSIE
+        set_csr!(0x144, SSIP); // sip.SSIP
+    }
+}
+
+fn arm_sext() {
+    unsafe {
+        set_csr!(0x104, SEIP); // sie.SEIE
+    }
+    arm_uart_irq('S');
+}
+
+fn arm_usoft_s() {
+    unsafe {
+        set_csr!(0x104, USIP); // sie.USIE
+        set_csr!(0x144, USIP); // sip.USIP
+    }
+}
+
+fn arm_usoft_u() {
+    unsafe {
+        set_csr!(0x103, USIP); // sideleg.USIP
+        set_csr!(0x004, USIP); // uie.USIE
+        set_csr!(0x144, USIP); // sip.USIP
+    }
+}
+
+fn arm_uext_u() {
+    unsafe {
+        set_csr!(0x103, UEIP); // sideleg.UEIP
+        set_csr!(0x004, UEIP); // uie.UEIE
+    }
+    arm_uart_irq('U');
+}
+
+static SOURCES: &[Source] = &[
+    Source {
+        name: "SupervisorTimer",
+        cause: cause::SUPERVISOR_TIMER,
+        user: false,
+        arm: arm_stimer,
+    },
+    Source {
+        name: "SupervisorSoft",
+        cause: cause::SUPERVISOR_SOFT,
+        user: false,
+        arm: arm_ssoft,
+    },
+    Source {
+        name: "SupervisorExternal",
+        cause: cause::SUPERVISOR_EXTERNAL,
+        user: false,
+        arm: arm_sext,
+    },
+    Source {
+        name: "UserSoft (S)",
+        cause: cause::USER_SOFT,
+        user: false,
+        arm: arm_usoft_s,
+    },
+    Source {
+        name: "UserSoft (U)",
+        cause: cause::USER_SOFT,
+        user: true,
+        arm: arm_usoft_u,
+    },
+    Source {
+        name: "UserExternal (U)",
+        cause: cause::USER_EXTERNAL,
+        user: true,
+        arm: arm_uext_u,
+    },
+];
+
+fn on_env_call(cx: &mut TrapContext, _scause: usize) {
+    if cx.x[17] == trap::USER_EXIT {
+        trap::leave_user(cx.x[10]);
+    }
+    cx.sepc += 4;
+    (SOURCES[SOURCE.load(SeqCst)].arm)();
+}
+
+fn on_interrupt(_cx: &mut TrapContext, scause: usize) {
+    TAKEN.store(scause, SeqCst);
+    match scause {
+        cause::SUPERVISOR_TIMER => {
+            set_timer(usize::MAX);
+            unsafe {
+                clear_csr!(0x104, STIP); // sie.STIE
+            }
+        }
+        cause::SUPERVISOR_SOFT => unsafe {
+            clear_csr!(0x144, SSIP); // sip.SSIP
+        },
+        cause::USER_SOFT => unsafe {
+            clear_csr!(0x144, USIP); // sip.USIP
+        },
+        _ => disarm_uart_irq('S'),
+    }
+}
+
+/// Runs in U-mode
+fn on_user_interrupt(_cx: &mut UserTrapContext, ucause: usize) {
+    TAKEN.store(ucause, SeqCst);
+    match ucause {
+        cause::USER_SOFT => unsafe {
+            clear_csr!(0x044, USIP); // uip.USIP
+        },
+        _ => disarm_uart_irq('U'),
+    }
+}
+
+fn expected(reg: usize) -> usize {
+    PATTERN | reg << 8 | reg
+}
+
+/// Returns `true` if the trap was taken where expected and no register but
+/// tp changed.
+fn check(source: &Source, regs: &[usize; 32], sp: usize) -> bool {
+    let mut ok = true;
+    let taken = TAKEN.load(SeqCst);
+    if taken != source.cause {
+        error!(
+            "[REGS] {}: took cause {:#x}, expected {:#x}",
+            source.name, taken, source.cause
+        );
+        ok = false;
+    }
+    for (reg, &value) in regs.iter().enumerate().skip(1) {
+        let expect = match reg {
+            2 => sp,
+            17 => ARM,
+            _ => expected(reg),
+        };
+        if value == expect {
+            continue;
+        }
+        if reg == 4 {
+            warn!(
+                "[REGS] {}: tp = {:#x}, expected {:#x} (not saved by trap.asm)",
+                source.name, value, expect
+            );
+        } else {
+            error!(
+                "[REGS] {}: x{} = {:#x}, expected {:#x}",
+                source.name, reg, value, expect
+            );
+            ok = false;
+        }
+    }
+    ok
+}
+
+/// Passes if every register survived every source.
+pub fn run() -> Outcome {
+    let saved = unsafe {
+        [
+            read_csr!(0x000), // ustatus
+            read_csr!(0x004), // uie
+            read_csr!(0x005), // utvec
+            read_csr!(0x103), // sideleg
+            read_csr!(0x104), // sie
+        ]
+    };
+    unsafe {
+        sstatus::clear_sie();
+        set_csr!(0x000, 1 << 0); // ustatus.UIE
+    }
+    trap::init_u();
+
+    let user_sp = stack::USER_STACK.get_sp();
+    let buffer_sp = user_sp - 32 * 8;
+    let previous_env_call = trap::register(cause::USER_ENV_CALL, on_env_call);
+    let mut passed = 0;
+    for (i, source) in SOURCES.iter().enumerate() {
+        SOURCE.store(i, SeqCst);
+        TAKEN.store(usize::MAX, SeqCst);
+        let (previous, previous_user) = if source.user {
+            (None, trap::register_user(source.cause, on_user_interrupt))
+        } else {
+            (trap::register(source.cause, on_interrupt), None)
+        };
+        let sp = trap::run_user(__regs_stress as usize, user_sp);
+        if source.user {
+            trap::set_user_handler(source.cause, previous_user);
+        } else {
+            trap::set_handler(source.cause, previous);
+        }
+        unsafe {
+            write_csr!(0x103, saved[3]);
+            write_csr!(0x004, saved[1]);
+        }
+        let regs = unsafe { &*(sp as *const [usize; 32]) };
+        if sp == buffer_sp && check(source, regs, sp) {
+            info!("[REGS] {:<20} PASS", source.name);
+            passed += 1;
+        } else {
+            error!("[REGS] {:<20} FAIL (buffer at {:#x})", source.name, sp);
+        }
+    }
+
+    trap::set_handler(cause::USER_ENV_CALL, previous_env_call);
+    unsafe {
+        write_csr!(0x000, saved[0]);
+        write_csr!(0x005, saved[2]);
+        write_csr!(0x104, saved[4]);
+    }
+    (passed == SOURCES.len()).into()
+}
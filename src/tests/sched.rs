@@ -0,0 +1,79 @@
+//! Preemption and yield across U-mode tasks.
+//!
+//! Every worker spins until all workers made progress. Spinners never give
+//! the CPU away on their own, so they only finish if the S-mode timer
+//! preempts them; the last worker yields after every step instead. Once a
+//! worker is done it yields too while waiting for the others.
+
+use super::Outcome;
+use crate::{syscall, task, CLOCK_FREQ};
+use core::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+const WORKERS: usize = 3;
+const STEPS: usize = 1000;
+/// Give up after this many `time` ticks
+const TIMEOUT: usize = CLOCK_FREQ * 2;
+
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+static PROGRESS: [AtomicUsize; WORKERS] = [ZERO; WORKERS];
+
+/// Through the syscall, `time` may not be readable from U-mode
+fn now() -> usize {
+    let ticks: usize;
+    unsafe {
+        asm!("ecall", lateout("a0") ticks, in("a7") syscall::SYSCALL_GET_TIME);
+    }
+    ticks
+}
+
+fn sys_yield() {
+    unsafe {
+        asm!("ecall", lateout("a0") _, in("a7") syscall::SYSCALL_YIELD);
+    }
+}
+
+/// Runs in U-mode
+extern "C" fn worker(id: usize) -> usize {
+    let deadline = now() + TIMEOUT;
+    while PROGRESS.iter().any(|p| p.load(Relaxed) < STEPS) {
+        if PROGRESS[id].load(Relaxed) < STEPS {
+            PROGRESS[id].fetch_add(1, Relaxed);
+        }
+        // Done spinners step aside so the yielder isn't paying two full
+        // slices for every step
+        if id == WORKERS - 1 || PROGRESS[id].load(Relaxed) >= STEPS {
+            sys_yield();
+        }
+        if now() > deadline {
+            return 1;
+        }
+    }
+    0
+}
+
+pub fn run() -> Outcome {
+    PROGRESS.iter().for_each(|p| p.store(0, Relaxed));
+    for id in 0..WORKERS {
+        if task::spawn(worker, id).is_none() {
+            error!("[SCHED] no free task slot");
+            return Outcome::Fail;
+        }
+    }
+    let report = task::run();
+    let mut ok = report.switches >= WORKERS;
+    for (id, code) in report.exit_codes.iter().take(WORKERS).enumerate() {
+        if *code != Some(0) {
+            error!("[SCHED] worker {} exited with {:?}", id, code);
+            ok = false;
+        }
+    }
+    if ok {
+        info!(
+            "[SCHED] {} workers PASS, {} switches",
+            WORKERS, report.switches
+        );
+    } else {
+        error!("[SCHED] FAIL, {} switches", report.switches);
+    }
+    ok.into()
+}
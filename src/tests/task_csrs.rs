@@ -0,0 +1,102 @@
+//! The N-extension CSRs are per task.
+//!
+//! The owner task installs a trap vector, enables USIE and pends USIP with
+//! `UIE` clear so the interrupt stays pending, then waits. The observer task
+//! starts only after that and must see a fresh state. When the owner runs
+//! again its vector and pending bit have to be back.
+
+use super::Outcome;
+use crate::{syscall, task, uintr};
+use core::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+const USIP: usize = 1 << 0;
+/// Never taken, only compared
+const VECTOR: usize = 0x8000_1000;
+
+const IDLE: usize = 0;
+const ARMED: usize = 1;
+const OBSERVED: usize = 2;
+
+static STAGE: AtomicUsize = AtomicUsize::new(IDLE);
+/// `uip`, `uie` and `utvec` as seen by the observer
+static SEEN: [AtomicUsize; 3] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+fn sys_yield() {
+    unsafe {
+        asm!("ecall", lateout("a0") _, in("a7") syscall::SYSCALL_YIELD);
+    }
+}
+
+fn wait_for(stage: usize) {
+    while STAGE.load(SeqCst) != stage {
+        sys_yield();
+    }
+}
+
+/// Runs in U-mode, fails with 1 if its state was not preserved
+extern "C" fn owner(_: usize) -> usize {
+    unsafe {
+        clear_csr!(0x000, 1); // ustatus.UIE
+        write_csr!(0x005, VECTOR);
+        set_csr!(0x004, USIP);
+        set_csr!(0x044, USIP);
+    }
+    STAGE.store(ARMED, SeqCst);
+    wait_for(OBSERVED);
+    let (uip, utvec) = unsafe { (read_csr!(0x044), read_csr!(0x005)) };
+    unsafe {
+        clear_csr!(0x044, USIP);
+    }
+    (uip & USIP == 0 || utvec != VECTOR) as usize
+}
+
+/// Runs in U-mode
+extern "C" fn observer(_: usize) -> usize {
+    wait_for(ARMED);
+    unsafe {
+        SEEN[0].store(read_csr!(0x044), SeqCst);
+        SEEN[1].store(read_csr!(0x004), SeqCst);
+        SEEN[2].store(read_csr!(0x005), SeqCst);
+    }
+    STAGE.store(OBSERVED, SeqCst);
+    0
+}
+
+pub fn run() -> Outcome {
+    // Otherwise S-mode would take the pending USIP while the owner waits
+    if let Some(backend) = uintr::backend() {
+        backend.delegate(uintr::SOFT);
+    }
+    STAGE.store(IDLE, SeqCst);
+    task::spawn(owner, 0);
+    task::spawn(observer, 0);
+    let report = task::run();
+    let mut ok = true;
+    if report.exit_codes[0] != Some(0) {
+        error!(
+            "[TASK_CSRS] owner lost its state: {:?}",
+            report.exit_codes[0]
+        );
+        ok = false;
+    }
+    let seen = [
+        SEEN[0].load(SeqCst),
+        SEEN[1].load(SeqCst),
+        SEEN[2].load(SeqCst),
+    ];
+    if seen[0] & USIP != 0 || seen[1] & USIP != 0 || seen[2] == VECTOR {
+        error!(
+            "[TASK_CSRS] leaked into observer: uip = {:#x}, uie = {:#x}, utvec = {:#x}",
+            seen[0], seen[1], seen[2]
+        );
+        ok = false;
+    }
+    if ok {
+        info!("[TASK_CSRS] PASS");
+    }
+    ok.into()
+}
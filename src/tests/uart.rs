@@ -0,0 +1,458 @@
+//! UART tests: throughput of the polling and the interrupt-driven drivers,
+//! the latter in S- and U-mode, the non-blocking semantics of the polling
+//! driver and, on the LRV board, the AXI UART Lite.
+//!
+//! `uart1` and `uart2` are wired to each other, see `SERIAL_FLAGS` in the
+//! justfile.
+
+use super::Outcome;
+use crate::{
+    sbi::set_timer,
+    stack, trap, uintr,
+    user_uart::{
+        self, get_base_addr_from_irq, Blocking, BufferedSerial, PollingSerial, RxTrigger,
+        FIFO_DEPTH, LSR, REG_OFFSET, REG_STRIDE,
+    },
+    BAUD_RATE, CLOCK_FREQ, IS_TIMEOUT,
+};
+use core::sync::atomic::Ordering::Relaxed;
+use embedded_hal::serial::{Read, Write};
+use riscv::register::{sie, sstatus, time};
+#[cfg(feature = "board_lrv")]
+use {crate::plic, uart_xilinx::uart_lite::MmioUartAxiLite};
+
+/// Every window of the speed test ends on the S-mode timer
+pub fn setup() {
+    IS_TIMEOUT.store(false, Relaxed);
+    unsafe {
+        sie::set_stimer();
+        sstatus::set_sie();
+    }
+}
+
+pub fn teardown() {
+    set_timer(usize::MAX);
+}
+
+/// Sends 64 bytes through the AXI UART Lite and checks its status register:
+/// interrupts enabled, Tx FIFO drained, nothing overrun or mangled on Rx.
+#[cfg(feature = "board_lrv")]
+pub fn lite() -> Outcome {
+    // Status register bits, see the AXI UART Lite product guide
+    const RX_FIFO_FULL: u8 = 1 << 1;
+    const TX_FIFO_EMPTY: u8 = 1 << 2;
+    const TX_FIFO_FULL: u8 = 1 << 3;
+    const INTR_ENABLED: u8 = 1 << 4;
+    const OVERRUN_ERROR: u8 = 1 << 5;
+    const FRAME_ERROR: u8 = 1 << 6;
+    const PARITY_ERROR: u8 = 1 << 7;
+
+    plic::init();
+    let uart = MmioUartAxiLite::new(0x6000_0000);
+    uart.enable_interrupt();
+    let status = || uart.status().bits();
+    let deadline = time::read() + CLOCK_FREQ;
+    for i in 0..64 {
+        while status() & TX_FIFO_FULL != 0 && time::read() < deadline {}
+        uart.write_byte(i as u8 + b'A');
+    }
+    while status() & TX_FIFO_EMPTY == 0 && time::read() < deadline {}
+    let status = status();
+    info!("[LITE] status: {:#x}", status);
+    plic::handle_external_interrupt();
+
+    let mut ok = true;
+    let mut expect = |what: &str, mask: u8, expected: u8| {
+        if status & mask != expected {
+            error!("[LITE] {} wrong in status {:#x}", what, status);
+            ok = false;
+        }
+    };
+    expect("interrupt enable", INTR_ENABLED, INTR_ENABLED);
+    expect("Tx FIFO empty", TX_FIFO_EMPTY | TX_FIFO_FULL, TX_FIFO_EMPTY);
+    expect("Rx FIFO full", RX_FIFO_FULL, 0);
+    expect("Rx errors", OVERRUN_ERROR | FRAME_ERROR | PARITY_ERROR, 0);
+    ok.into()
+}
+
+#[cfg(not(feature = "board_lrv"))]
+pub fn lite() -> Outcome {
+    Outcome::Skip
+}
+
+/// Register block of a UART that never sends or receives anything on its
+/// own, the test sets `LSR` and `RBR` by hand
+#[repr(C, align(8))]
+struct FakeRegisters([u8; REG_OFFSET + 8 * REG_STRIDE]);
+
+static mut FAKE_UART: FakeRegisters = FakeRegisters([0; REG_OFFSET + 8 * REG_STRIDE]);
+
+const RBR: usize = 0;
+const LSR_REG: usize = 5;
+/// `LSR` data ready
+const LSR_DR: u8 = 1;
+
+fn set_fake(reg: usize, value: u8) {
+    unsafe {
+        let regs = FAKE_UART.0.as_mut_ptr();
+        regs.add(REG_OFFSET + reg * REG_STRIDE)
+            .write_volatile(value);
+    }
+}
+
+/// `PollingSerial` never spins. On a fake UART whose `LSR` never reports THRE
+/// exactly one FIFO's worth of writes goes through before `WouldBlock`, and
+/// flushing and reading block too. Once `LSR` says the FIFO drained and data
+/// arrived, all of them complete.
+pub fn nb_semantics() -> Outcome {
+    let mut ok = true;
+    let mut expect = |what: &str, good: bool| {
+        if !good {
+            error!("[UART] {}", what);
+            ok = false;
+        }
+    };
+    set_fake(LSR_REG, 0);
+    let mut uart = PollingSerial::new(unsafe { FAKE_UART.0.as_ptr() } as usize);
+
+    let written = (0..2 * FIFO_DEPTH)
+        .take_while(|_| uart.try_write(0x55).is_ok())
+        .count();
+    info!("[UART] WouldBlock after {} bytes", written);
+    expect("Tx FIFO size not respected", written == FIFO_DEPTH);
+    expect(
+        "flush completed without TEMT",
+        matches!(uart.try_flush(), Err(nb::Error::WouldBlock)),
+    );
+    expect(
+        "read completed without data",
+        matches!(uart.try_read(), Err(nb::Error::WouldBlock)),
+    );
+
+    set_fake(RBR, 0x5a);
+    set_fake(LSR_REG, (LSR::THRE | LSR::TEMT).bits() | LSR_DR);
+    expect(
+        "write to a drained FIFO blocked",
+        uart.try_write(0x55).is_ok(),
+    );
+    expect("flush blocked with TEMT", uart.try_flush().is_ok());
+    let mut buf = [0; 4];
+    let read = Blocking(&mut uart).read_exact(&mut buf);
+    expect("read blocked with data", read.is_ok() && buf == [0x5a; 4]);
+    expect(
+        "bytes miscounted",
+        uart.tx_count == FIFO_DEPTH + 1 && uart.rx_count == 4,
+    );
+    ok.into()
+}
+
+/// Measuring window of a single run
+const WINDOW: usize = CLOCK_FREQ / 10;
+const REPEATS: usize = 3;
+const BURSTS: [usize; 4] = [1, 8, 14, 32];
+/// Empty read rounds timed to price one round
+const CALIBRATION_ROUNDS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Polling,
+    Kernel,
+    User,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Sample {
+    /// Bytes received by both serials
+    rx: usize,
+    interrupts: usize,
+    /// Read rounds that found nothing, the CPU was free for something else
+    spare: usize,
+}
+
+impl core::ops::AddAssign for Sample {
+    fn add_assign(&mut self, other: Self) {
+        self.rx += other.rx;
+        self.interrupts += other.interrupts;
+        self.spare += other.spare;
+    }
+}
+
+static mut USER_SPARE: usize = 0;
+
+/// Until `IS_TIMEOUT`: both serials send `burst` bytes to each other, then
+/// wait for them. Returns the bytes received, `spare` counts the rounds
+/// the wait found nothing to read.
+fn pump<S>(uart1: &mut S, uart2: &mut S, burst: usize, spare: &mut usize) -> usize
+where
+    S: Read<u8> + Write<u8>,
+{
+    let mut rx = 0;
+    while !IS_TIMEOUT.load(Relaxed) {
+        let mut sent = 0;
+        for _ in 0..burst {
+            sent += uart1.try_write(0x55).is_ok() as usize;
+            sent += uart2.try_write(0x55).is_ok() as usize;
+        }
+        let mut got = 0;
+        while got < sent && !IS_TIMEOUT.load(Relaxed) {
+            let round = uart1.try_read().is_ok() as usize + uart2.try_read().is_ok() as usize;
+            if round == 0 {
+                *spare += 1;
+            }
+            got += round;
+        }
+        rx += got;
+    }
+    rx
+}
+
+/// `time` ticks of `rounds` empty read rounds on the idle serials
+fn spare_cost<S: Read<u8>>(uart1: &mut S, uart2: &mut S, rounds: usize) -> usize {
+    let start = time::read();
+    for _ in 0..rounds {
+        let _ = uart1.try_read();
+        let _ = uart2.try_read();
+    }
+    time::read() - start
+}
+
+fn arm_window() {
+    IS_TIMEOUT.store(false, Relaxed);
+    unsafe { sie::set_stimer() };
+    set_timer(time::read() + WINDOW);
+}
+
+/// Runs in U-mode
+extern "C" fn user_pump(burst: usize, _: usize, _: usize, _: usize) -> usize {
+    let backend = uintr::backend().unwrap();
+    trap::init_u();
+    backend.enable(uintr::EXTERNAL);
+    backend.set_global(true);
+    let mut uart1 = user_uart::buffered_serial(0).unwrap();
+    let mut uart2 = user_uart::buffered_serial(1).unwrap();
+    let rx = pump(&mut uart1, &mut uart2, burst, unsafe { &mut USER_SPARE });
+    backend.set_global(false);
+    rx
+}
+
+/// One window in `mode`, and the price of a spare round in ticks per
+/// [`CALIBRATION_ROUNDS`]. Polling has no spare rounds: an empty read is
+/// how it waits for data.
+fn measure(mode: Mode, trigger: RxTrigger, burst: usize) -> (Sample, usize) {
+    #[cfg(feature = "board_qemu")]
+    let irqs = (14, 15);
+    #[cfg(feature = "board_lrv")]
+    let irqs = (6, 7);
+
+    if mode == Mode::Polling {
+        let mut uart1 = PollingSerial::new(get_base_addr_from_irq(irqs.0));
+        let mut uart2 = PollingSerial::new(get_base_addr_from_irq(irqs.1));
+        uart1.hardware_init(BAUD_RATE);
+        uart2.hardware_init(BAUD_RATE);
+        arm_window();
+        let rx = pump(&mut uart1, &mut uart2, burst, &mut 0);
+        let sample = Sample {
+            rx,
+            ..Sample::default()
+        };
+        return (sample, 0);
+    }
+
+    let backend = uintr::backend();
+    user_uart::init_buffered_serials(if mode == Mode::User { 'U' } else { 'S' }, BAUD_RATE);
+    let mut uart1 = user_uart::buffered_serial(0).unwrap();
+    let mut uart2 = user_uart::buffered_serial(1).unwrap();
+    uart1.set_rx_trigger(trigger);
+    uart2.set_rx_trigger(trigger);
+    let cost = spare_cost(&mut uart1, &mut uart2, CALIBRATION_ROUNDS);
+
+    let mut spare = 0;
+    arm_window();
+    let rx = match (mode, backend) {
+        (Mode::User, Some(backend)) => {
+            unsafe { USER_SPARE = 0 };
+            backend.delegate(uintr::EXTERNAL);
+            let rx = trap::call_user(user_pump, [burst, 0, 0, 0], stack::USER_STACK.get_sp());
+            backend.undelegate(uintr::EXTERNAL);
+            spare = unsafe { USER_SPARE };
+            rx
+        }
+        _ => pump(&mut uart1, &mut uart2, burst, &mut spare),
+    };
+    let sample = Sample {
+        rx,
+        interrupts: uart1.intr_count.load(Relaxed) + uart2.intr_count.load(Relaxed),
+        spare,
+    };
+    user_uart::deinit_buffered_serials();
+    (sample, cost)
+}
+
+/// Same traffic through the polling, S-mode interrupt and U-mode interrupt
+/// drivers, sweeping Rx trigger levels (interrupt modes only) and bursts.
+/// Throughput is given as the slowest and fastest of [`REPEATS`] windows.
+/// CPU time per byte is the part of the window not spent in spare rounds,
+/// polling has none so it is left out there. Fails if any configuration
+/// received nothing at all.
+pub fn speed() -> Outcome {
+    // The PLIC U-context needs the real thing, emulation can't route it
+    let user = crate::features::has_n_extension();
+    if !user {
+        info!("[UART] no N extension, U-mode driver left out");
+    }
+    info!("[UART] mode     trigger burst  min bytes/s  max bytes/s  interrupts  ticks/byte");
+    let mut ok = true;
+    for &mode in [Mode::Polling, Mode::Kernel, Mode::User].iter() {
+        if mode == Mode::User && !user {
+            continue;
+        }
+        let triggers: &[RxTrigger] = match mode {
+            Mode::Polling => &RxTrigger::ALL[..1],
+            _ => &RxTrigger::ALL,
+        };
+        for &trigger in triggers {
+            for &burst in BURSTS.iter() {
+                let mut total = Sample::default();
+                let mut cost = 0;
+                let (mut min_rx, mut max_rx) = (usize::MAX, 0);
+                for _ in 0..REPEATS {
+                    let (sample, c) = measure(mode, trigger, burst);
+                    min_rx = min_rx.min(sample.rx);
+                    max_rx = max_rx.max(sample.rx);
+                    total += sample;
+                    cost += c;
+                }
+                let window = WINDOW * REPEATS;
+                let spare_ticks = total.spare * (cost / REPEATS) / CALIBRATION_ROUNDS;
+                let busy = window.saturating_sub(spare_ticks);
+                let trigger = match mode {
+                    Mode::Polling => 0,
+                    _ => trigger.bytes(),
+                };
+                let ticks_per_byte = busy.checked_div(total.rx).unwrap_or(0);
+                let ticks_per_byte: &dyn core::fmt::Display = match mode {
+                    Mode::Polling => &"-",
+                    _ => &ticks_per_byte,
+                };
+                info!(
+                    "[UART] {:<8} {:>7} {:>5} {:>12} {:>12} {:>11} {:>11}",
+                    match mode {
+                        Mode::Polling => "polling",
+                        Mode::Kernel => "S-mode",
+                        Mode::User => "U-mode",
+                    },
+                    trigger,
+                    burst,
+                    min_rx * CLOCK_FREQ / WINDOW,
+                    max_rx * CLOCK_FREQ / WINDOW,
+                    total.interrupts / REPEATS,
+                    ticks_per_byte
+                );
+                // A driver that moves nothing is broken, not slow
+                if total.rx == 0 {
+                    error!("[UART] {:?} moved no data", mode);
+                    ok = false;
+                }
+            }
+        }
+    }
+    ok.into()
+}
+
+const MESSAGE: &[u8] = b"uart driven by interrupts through ring buffers";
+
+/// Move what `serial` received so far into `buf[len..]`, returns the new
+/// length.
+fn drain(mut serial: &BufferedSerial, buf: &mut [u8], mut len: usize) -> usize {
+    while len < buf.len() {
+        match serial.try_read() {
+            Ok(ch) => buf[len] = ch,
+            Err(_) => break,
+        }
+        len += 1;
+    }
+    len
+}
+
+/// Each buffered serial sends [`MESSAGE`] to the other one, `true` if both
+/// got it intact within a second. Works in S- and U-mode, `now` is `time`.
+fn exchange(now: fn() -> usize) -> bool {
+    let (mut uart1, mut uart2) =
+        match (user_uart::buffered_serial(0), user_uart::buffered_serial(1)) {
+            (Some(uart1), Some(uart2)) => (uart1, uart2),
+            _ => return false,
+        };
+    let _ = Blocking(&mut uart1).write_all(MESSAGE);
+    let _ = Blocking(&mut uart2).write_all(MESSAGE);
+    let deadline = now() + CLOCK_FREQ;
+    let mut received = [[0; MESSAGE.len()]; 2];
+    let (mut len1, mut len2) = (0, 0);
+    while len1 < MESSAGE.len() || len2 < MESSAGE.len() {
+        len1 = drain(uart1, &mut received[0], len1);
+        len2 = drain(uart2, &mut received[1], len2);
+        if now() > deadline {
+            break;
+        }
+    }
+    received.iter().all(|buf| buf[..] == *MESSAGE)
+}
+
+fn report() {
+    for id in 0..2 {
+        let serial = user_uart::buffered_serial(id).unwrap();
+        info!(
+            "uart{} rx {}, tx {}, interrupts {}, dropped {}",
+            id + 1,
+            serial.rx_count.load(Relaxed),
+            serial.tx_count.load(Relaxed),
+            serial.intr_count.load(Relaxed),
+            serial.rx_dropped.load(Relaxed)
+        );
+    }
+}
+
+/// `uart1` and `uart2` on the S-context: the Rx FIFO is drained and the Tx
+/// FIFO refilled from `plic::handle_external_interrupt`.
+pub fn kernel_irq() -> Outcome {
+    user_uart::init_buffered_serials('S', BAUD_RATE);
+    unsafe {
+        sie::set_sext();
+        sstatus::set_sie();
+    }
+    let ok = exchange(time::read);
+    unsafe {
+        sstatus::clear_sie();
+    }
+    report();
+    user_uart::deinit_buffered_serials();
+    ok.into()
+}
+
+fn user_time() -> usize {
+    user_lib::syscall::get_time() as usize
+}
+
+/// Runs in U-mode
+extern "C" fn user_irq_main(_: usize, _: usize, _: usize, _: usize) -> usize {
+    let backend = uintr::backend().unwrap();
+    trap::init_u();
+    backend.enable(uintr::EXTERNAL);
+    backend.set_global(true);
+    let ok = exchange(user_time);
+    backend.set_global(false);
+    !ok as usize
+}
+
+/// `uart1` and `uart2` handled entirely in U-mode: the PLIC U-context raises
+/// `UserExternal`, the handler claims, drains or refills the FIFOs and
+/// completes without entering S-mode.
+pub fn user_irq() -> Outcome {
+    let backend = uintr::backend().unwrap();
+    user_uart::init_buffered_serials('U', BAUD_RATE);
+    backend.delegate(uintr::EXTERNAL);
+    let ret = trap::call_user(user_irq_main, [0; 4], stack::USER_STACK.get_sp());
+    backend.undelegate(uintr::EXTERNAL);
+    report();
+    user_uart::deinit_buffered_serials();
+    (ret == 0).into()
+}
@@ -0,0 +1,146 @@
+//! Deferred delivery of a task-owned external interrupt.
+//!
+//! `UART_IRQ` is routed to the owner task. In every round the owner raises
+//! it itself, so it is delivered directly, then asks the busy task to raise
+//! it while the owner is descheduled, so it has to be queued and injected
+//! when the owner runs again. Passes if every IRQ reached the owner's
+//! handler, and prints both claim latencies.
+
+use super::{uart, Outcome, UART_IRQ};
+use crate::{
+    plic::{self, Plic},
+    syscall, task, trap,
+    trap::{cause, UserTrapContext},
+    uintr, uirq,
+};
+use core::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+use rv_plic::Priority;
+
+const ROUNDS: usize = 4;
+const UIE: usize = 1 << 0;
+const UEIE: usize = 1 << 8;
+
+const RUNNING: usize = 0;
+const ARM_REQUEST: usize = 1;
+const DONE: usize = 2;
+
+static STAGE: AtomicUsize = AtomicUsize::new(RUNNING);
+static HANDLED: AtomicUsize = AtomicUsize::new(0);
+
+fn ecall(id: usize, a0: usize) -> usize {
+    let ret;
+    unsafe {
+        asm!("ecall", inlateout("a0") a0 => ret, in("a7") id);
+    }
+    ret
+}
+
+/// Runs in U-mode, in the owner task
+fn on_external(_cx: &mut UserTrapContext, _ucause: usize) {
+    let irq = ecall(syscall::SYSCALL_IRQ_CLAIM, 0);
+    if irq == UART_IRQ as usize {
+        uart().write_ier(0);
+        ecall(syscall::SYSCALL_IRQ_COMPLETE, irq);
+        HANDLED.fetch_add(1, SeqCst);
+    }
+}
+
+/// Spin, yielding, until `HANDLED` reaches `count`. `false` on timeout.
+fn wait_handled(count: usize, deadline: usize) -> bool {
+    while HANDLED.load(SeqCst) < count {
+        if ecall(syscall::SYSCALL_GET_TIME, 0) > deadline {
+            return false;
+        }
+        ecall(syscall::SYSCALL_YIELD, 0);
+    }
+    true
+}
+
+/// Runs in U-mode
+extern "C" fn owner(_: usize) -> usize {
+    trap::init_u();
+    unsafe {
+        set_csr!(0x004, UEIE);
+        set_csr!(0x000, UIE);
+    }
+    let deadline = ecall(syscall::SYSCALL_GET_TIME, 0) + crate::CLOCK_FREQ * 2;
+    let mut ok = true;
+    for round in 0..ROUNDS {
+        // Direct: taken on the way back from the S-mode trap
+        uart().write_ier(0b10);
+        ok &= wait_handled(2 * round + 1, deadline);
+        // Deferred: raised while the busy task runs
+        STAGE.store(ARM_REQUEST, SeqCst);
+        ok &= wait_handled(2 * round + 2, deadline);
+    }
+    STAGE.store(DONE, SeqCst);
+    !ok as usize
+}
+
+/// Runs in U-mode
+extern "C" fn busy(_: usize) -> usize {
+    loop {
+        match STAGE.load(SeqCst) {
+            DONE => return 0,
+            ARM_REQUEST => {
+                STAGE.store(RUNNING, SeqCst);
+                uart().write_ier(0b10);
+            }
+            _ => {}
+        }
+        ecall(syscall::SYSCALL_YIELD, 0);
+    }
+}
+
+pub fn run() -> Outcome {
+    let ctx = plic::get_context(0, 'S');
+    Plic::set_priority(UART_IRQ, Priority::lowest());
+    Plic::set_threshold(ctx, Priority::any());
+    Plic::enable(ctx, UART_IRQ);
+    unsafe {
+        set_csr!(0x104, 1 << 9); // sie.SEIE
+    }
+    if let Some(backend) = uintr::backend() {
+        backend.undelegate(uintr::EXTERNAL);
+    }
+    trap::register_user(cause::USER_EXTERNAL, on_external);
+    STAGE.store(RUNNING, SeqCst);
+    HANDLED.store(0, SeqCst);
+
+    uirq::reset();
+    let owner_id = task::spawn(owner, 0).unwrap();
+    task::spawn(busy, 0);
+    uirq::route(UART_IRQ, owner_id);
+    let report = task::run();
+    let (direct, deferred) = uirq::latency();
+    uirq::reset();
+
+    uart().write_ier(0);
+    Plic::disable(ctx, UART_IRQ);
+
+    let handled = HANDLED.load(SeqCst);
+    info!(
+        "[UIRQ] direct: {} IRQs, avg {} / max {} ticks",
+        direct.count,
+        direct.average(),
+        direct.max
+    );
+    info!(
+        "[UIRQ] deferred: {} IRQs, avg {} / max {} ticks",
+        deferred.count,
+        deferred.average(),
+        deferred.max
+    );
+    let ok = report.exit_codes[owner_id] == Some(0) && handled == 2 * ROUNDS;
+    if ok {
+        info!("[UIRQ] PASS");
+    } else {
+        error!(
+            "[UIRQ] FAIL: {} of {} IRQs handled, owner exited with {:?}",
+            handled,
+            2 * ROUNDS,
+            report.exit_codes[owner_id]
+        );
+    }
+    ok.into()
+}
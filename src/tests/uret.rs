@@ -0,0 +1,189 @@
+//! What `__alltraps_u`/`__restore_u` and `uret` do to `ustatus` and `uepc`.
+//!
+//! A U-mode routine pends a delegated user software interrupt twice with
+//! `UIE` set, then raises a delegated breakpoint with `UIE` clear. The handler
+//! and the routine record `ustatus`, `ucause` and `uepc` around every trap,
+//! S-mode checks the transitions afterwards:
+//!
+//! - trap entry: `UPIE <- UIE`, `UIE <- 0`, `uepc` = interrupted instruction
+//! - `uret`: `UIE <- UPIE`, `UPIE <- 1`, execution resumes at `uepc`
+
+use super::Outcome;
+use crate::{
+    stack, trap,
+    trap::{cause, UserTrapContext},
+};
+use riscv::register::sstatus;
+
+const UIE: usize = 1 << 0;
+const UPIE: usize = 1 << 4;
+const USIP: usize = 1 << 0;
+
+const INTERRUPT_ROUNDS: usize = 2;
+const ROUNDS: usize = INTERRUPT_ROUNDS + 1;
+
+#[derive(Debug, Clone, Copy)]
+struct Round {
+    /// `ustatus` right before the trap
+    before: usize,
+    /// Where the trap should be taken
+    expected_epc: usize,
+    /// `ustatus` seen by the handler
+    handler_ustatus: usize,
+    ucause: usize,
+    uepc: usize,
+    /// `ustatus` after `uret`
+    after: usize,
+    taken: usize,
+}
+
+const EMPTY: Round = Round {
+    before: 0,
+    expected_epc: 0,
+    handler_ustatus: 0,
+    ucause: 0,
+    uepc: 0,
+    after: 0,
+    taken: 0,
+};
+
+static mut ROUND: usize = 0;
+static mut RECORDS: [Round; ROUNDS] = [EMPTY; ROUNDS];
+
+/// Runs in U-mode
+fn on_user_trap(cx: &mut UserTrapContext, ucause: usize) {
+    let record = unsafe { &mut RECORDS[ROUND] };
+    record.handler_ustatus = unsafe { read_csr!(0x000) };
+    record.ucause = ucause;
+    record.uepc = cx.uepc;
+    record.taken += 1;
+    if ucause == cause::USER_SOFT {
+        unsafe {
+            clear_csr!(0x044, USIP);
+        }
+    } else {
+        cx.uepc += 4;
+    }
+}
+
+/// Runs in U-mode
+extern "C" fn routine() {
+    for round in 0..ROUNDS {
+        let record = unsafe {
+            ROUND = round;
+            &mut RECORDS[round]
+        };
+        let expected_epc: usize;
+        unsafe {
+            if round == INTERRUPT_ROUNDS {
+                clear_csr!(0x000, UIE);
+                record.before = read_csr!(0x000);
+                asm!(
+                    ".option push",
+                    ".option norvc",
+                    "la {0}, 1f",
+                    "1:",
+                    "ebreak",
+                    ".option pop",
+                    out(reg) expected_epc
+                );
+            } else {
+                record.before = read_csr!(0x000);
+                asm!(
+                    "la {0}, 1f",
+                    "csrsi 0x044, 1",
+                    "1:",
+                    "nop",
+                    out(reg) expected_epc
+                );
+            }
+            record.after = read_csr!(0x000);
+        }
+        record.expected_epc = expected_epc;
+    }
+    trap::exit_user(0)
+}
+
+fn check(round: usize, record: &Round) -> bool {
+    let interrupt = round < INTERRUPT_ROUNDS;
+    let uie_before = if interrupt { UIE } else { 0 };
+    let mut ok = true;
+    let mut expect = |what: &str, got: usize, expected: usize| {
+        if got != expected {
+            error!(
+                "[URET] round {}: {} = {:#x}, expected {:#x}",
+                round, what, got, expected
+            );
+            ok = false;
+        }
+    };
+    expect("trap count", record.taken, 1);
+    expect("UIE before trap", record.before & UIE, uie_before);
+    expect("UIE in handler", record.handler_ustatus & UIE, 0);
+    expect(
+        "UPIE in handler",
+        record.handler_ustatus & UPIE,
+        if interrupt { UPIE } else { 0 },
+    );
+    expect(
+        "ucause",
+        record.ucause,
+        if interrupt {
+            cause::USER_SOFT
+        } else {
+            cause::BREAKPOINT
+        },
+    );
+    expect("uepc", record.uepc, record.expected_epc);
+    expect("UIE after uret", record.after & UIE, uie_before);
+    expect("UPIE after uret", record.after & UPIE, UPIE);
+    ok
+}
+
+/// Passes if every transition matched the spec.
+pub fn run() -> Outcome {
+    let saved = unsafe {
+        [
+            read_csr!(0x000), // ustatus
+            read_csr!(0x004), // uie
+            read_csr!(0x005), // utvec
+            read_csr!(0x102), // sedeleg
+            read_csr!(0x103), // sideleg
+        ]
+    };
+    unsafe {
+        sstatus::clear_sie();
+        RECORDS = [EMPTY; ROUNDS];
+        set_csr!(0x103, USIP); // sideleg.USIP
+        set_csr!(0x102, 1 << cause::BREAKPOINT); // sedeleg.Breakpoint
+        set_csr!(0x004, USIP); // uie.USIE
+        set_csr!(0x000, UIE); // ustatus.UIE
+    }
+    trap::init_u();
+
+    let previous_soft = trap::register_user(cause::USER_SOFT, on_user_trap);
+    let previous_breakpoint = trap::register_user(cause::BREAKPOINT, on_user_trap);
+    trap::run_user(routine as usize, stack::USER_STACK.get_sp());
+    trap::set_user_handler(cause::USER_SOFT, previous_soft);
+    trap::set_user_handler(cause::BREAKPOINT, previous_breakpoint);
+
+    unsafe {
+        write_csr!(0x000, saved[0]);
+        write_csr!(0x004, saved[1]);
+        write_csr!(0x005, saved[2]);
+        write_csr!(0x102, saved[3]);
+        write_csr!(0x103, saved[4]);
+    }
+
+    let records = unsafe { RECORDS };
+    let ok = records
+        .iter()
+        .enumerate()
+        .fold(true, |ok, (round, record)| check(round, record) && ok);
+    if ok {
+        info!("[URET] {} rounds PASS", ROUNDS);
+    } else {
+        error!("[URET] FAIL");
+    }
+    ok.into()
+}
@@ -0,0 +1,213 @@
+//! WARL/WLRL conformance of the N-extension CSRs.
+//!
+//! Each CSR gets all-zeros, all-ones and walking-one patterns written, the
+//! value read back is checked against the legalization rules of the
+//! user-level interrupt spec. The original value is restored afterwards.
+
+use super::Outcome;
+use crate::csr_probe::{self, CsrEntry};
+use riscv::register::sstatus;
+
+const USIP: usize = 1 << 0;
+const UTIP: usize = 1 << 4;
+const UEIP: usize = 1 << 8;
+const USER_INTERRUPTS: usize = USIP | UTIP | UEIP;
+
+const UIE: usize = 1 << 0;
+const UPIE: usize = 1 << 4;
+
+/// Exception codes that may be delegated to U-mode, i.e. everything in
+/// 0..16 except ecall from S/H/M and the reserved code 14.
+const SEDELEG_LEGAL: usize = 0xffff & !(0b111 << 9) & !(1 << 14);
+
+/// Only legal values are guaranteed to be held by a WLRL field.
+const UCAUSE_LEGAL: &[usize] = &[0, 2, 3, 8, 1 << 63, (1 << 63) | 4, (1 << 63) | 8];
+
+/// Maximum failures printed per CSR
+const MAX_REPORTED: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    written: usize,
+    read: usize,
+    /// `sideleg` while the sample was taken, masks `uie`/`uip`
+    sideleg: usize,
+}
+
+enum Patterns {
+    Walking,
+    Values(&'static [usize]),
+}
+
+struct WarlRule {
+    csr: &'static str,
+    patterns: Patterns,
+    check: fn(Sample) -> bool,
+}
+
+fn subset(read: usize, allowed: usize) -> bool {
+    read & !allowed == 0
+}
+
+fn ustatus(s: Sample) -> bool {
+    s.read == s.written & (UIE | UPIE)
+}
+
+fn uie(s: Sample) -> bool {
+    s.read == s.written & USER_INTERRUPTS & s.sideleg
+}
+
+/// UTIP and UEIP are read-only and reflect the live pending state
+fn uip(s: Sample) -> bool {
+    subset(s.read, USER_INTERRUPTS & s.sideleg) && s.read & USIP == s.written & USIP & s.sideleg
+}
+
+/// MODE >= 2 is reserved, the write may be dropped or legalized
+fn utvec(s: Sample) -> bool {
+    s.read & 0b11 < 2 && (s.written & 0b11 >= 2 || s.read == s.written)
+}
+
+/// uepc[0] is always zero, uepc[1] too without the C extension
+fn uepc(s: Sample) -> bool {
+    s.read == s.written & !0b1 || s.read == s.written & !0b11
+}
+
+fn read_write(s: Sample) -> bool {
+    s.read == s.written
+}
+
+fn sideleg(s: Sample) -> bool {
+    subset(s.read, s.written & USER_INTERRUPTS)
+}
+
+fn sedeleg(s: Sample) -> bool {
+    subset(s.read, s.written & SEDELEG_LEGAL)
+}
+
+macro_rules! rule {
+    ($csr: literal, $patterns: expr, $check: expr) => {
+        WarlRule {
+            csr: $csr,
+            patterns: $patterns,
+            check: $check,
+        }
+    };
+}
+
+static RULES: &[WarlRule] = &[
+    rule!("ustatus", Patterns::Walking, ustatus),
+    rule!("uie", Patterns::Walking, uie),
+    rule!("uip", Patterns::Walking, uip),
+    rule!("utvec", Patterns::Walking, utvec),
+    rule!("uscratch", Patterns::Walking, read_write),
+    rule!("uepc", Patterns::Walking, uepc),
+    rule!("ucause", Patterns::Values(UCAUSE_LEGAL), read_write),
+    rule!("utval", Patterns::Walking, read_write),
+    rule!("sideleg", Patterns::Walking, sideleg),
+    rule!("sedeleg", Patterns::Walking, sedeleg),
+];
+
+fn find(name: &str) -> Option<&'static CsrEntry> {
+    csr_probe::CSR_TABLE.iter().find(|e| e.name == name)
+}
+
+/// Write `bits` and read it back, `None` if either access trapped.
+fn write_read(entry: &CsrEntry, bits: usize) -> Option<usize> {
+    csr_probe::try_access(|| (entry.write)(bits))?;
+    csr_probe::try_access(entry.read)
+}
+
+fn for_each_pattern(patterns: &Patterns, mut f: impl FnMut(usize)) {
+    match patterns {
+        Patterns::Walking => {
+            f(0);
+            f(usize::MAX);
+            (0..core::mem::size_of::<usize>() * 8).for_each(|i| f(1 << i));
+        }
+        Patterns::Values(values) => values.iter().copied().for_each(f),
+    }
+}
+
+/// Returns the number of failed samples, or `None` if the CSR is missing.
+fn check_rule(rule: &WarlRule, sideleg: Option<&CsrEntry>) -> Option<usize> {
+    let entry = find(rule.csr)?;
+    let original = csr_probe::try_access(entry.read)?;
+    let mut failed = 0;
+    let mut trapped = false;
+    for_each_pattern(&rule.patterns, |written| {
+        if trapped {
+            return;
+        }
+        let read = match write_read(entry, written) {
+            Some(read) => read,
+            None => {
+                trapped = true;
+                return;
+            }
+        };
+        let sample = Sample {
+            written,
+            read,
+            sideleg: sideleg.map_or(0, |e| (e.read)()),
+        };
+        if !(rule.check)(sample) {
+            if failed < MAX_REPORTED {
+                error!(
+                    "[WARL] {}: wrote {:#x}, read {:#x} (sideleg = {:#x})",
+                    rule.csr, sample.written, sample.read, sample.sideleg
+                );
+            }
+            failed += 1;
+        }
+    });
+    let _ = csr_probe::try_access(|| (entry.write)(original));
+    if trapped {
+        error!("[WARL] {}: write trapped", rule.csr);
+        failed += 1;
+    }
+    Some(failed)
+}
+
+/// Run the whole suite, passes if every CSR conforms.
+pub fn run() -> Outcome {
+    let sie = sstatus::read().sie();
+    unsafe {
+        sstatus::clear_sie();
+    }
+
+    let sideleg = find("sideleg").filter(|e| csr_probe::try_access(e.read).is_some());
+    let saved_sideleg = sideleg.map(|e| (e.read)());
+
+    let mut passed = 0;
+    for rule in RULES {
+        if let Some(e) = sideleg {
+            // Delegate every user interrupt so uie/uip bits become visible,
+            // the delegation registers themselves start from their original
+            // state
+            let bits = match rule.csr {
+                "sideleg" | "sedeleg" => saved_sideleg.unwrap_or(0),
+                _ => USER_INTERRUPTS,
+            };
+            let _ = csr_probe::try_access(|| (e.write)(bits));
+        }
+        match check_rule(rule, sideleg) {
+            Some(0) => {
+                info!("[WARL] {:>8}: PASS", rule.csr);
+                passed += 1;
+            }
+            Some(failed) => error!("[WARL] {:>8}: FAIL ({} samples)", rule.csr, failed),
+            None => error!("[WARL] {:>8}: FAIL (missing)", rule.csr),
+        }
+    }
+
+    if let (Some(e), Some(bits)) = (sideleg, saved_sideleg) {
+        let _ = csr_probe::try_access(|| (e.write)(bits));
+    }
+    if sie {
+        unsafe {
+            sstatus::set_sie();
+        }
+    }
+    info!("[WARL] {}/{} CSRs conform", passed, RULES.len());
+    (passed == RULES.len()).into()
+}
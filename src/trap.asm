@@ -4,15 +4,27 @@
 .endm
 .macro LOAD_GP n
     ld x\n, \n*8(sp)
+.endm
+.macro SAVE_S n
+    sd s\n, (\n+1)*8(sp)
+.endm
+.macro LOAD_S n
+    ld s\n, (\n+1)*8(sp)
 .endm
     .section .text
     .globl __alltraps
     .globl __restore
     .globl __alltraps_u
     .globl __restore_u
+# sscratch holds the kernel stack to trap onto while U-mode runs and is 0 in
+# S-mode, where traps are taken on the current stack.
     .align 2
 __alltraps:
-    csrw sscratch, sp
+    csrrw sp, sscratch, sp
+    bnez sp, 1f
+    # from S-mode: back to the interrupted stack, sscratch = 0
+    csrrw sp, sscratch, sp
+1:
     addi sp, sp, -34*8
     sd x1, 1*8(sp)
     sd x3, 3*8(sp)
@@ -25,8 +37,13 @@ __alltraps:
     csrr t1, sepc
     sd t0, 32*8(sp)
     sd t1, 33*8(sp)
+    # user sp, or 0 if the trap came from S-mode
     csrr t2, sscratch
+    bnez t2, 2f
+    addi t2, sp, 34*8
+2:
     sd t2, 2*8(sp)
+    csrw sscratch, zero
     mv  a0, sp # a0 = sp
     call trap_handler
 
@@ -34,10 +51,14 @@ __restore:
     mv sp, a0
     ld t0, 32*8(sp)
     ld t1, 33*8(sp)
-    ld t2, 2*8(sp)
     csrw sstatus, t0
     csrw sepc, t1
-    csrw sscratch, t2
+    # returning to U-mode: the next trap starts above this frame
+    andi t0, t0, 1 << 8
+    bnez t0, 3f
+    addi t1, sp, 34*8
+    csrw sscratch, t1
+3:
     ld x1, 1*8(sp)
     ld x3, 3*8(sp)
     .set n, 5
@@ -45,8 +66,7 @@ __restore:
         LOAD_GP %n
         .set n, n+1
     .endr
-    addi sp, sp, 34*8
-    csrr sp, sscratch
+    ld sp, 2*8(sp)
     sret
 
 __alltraps_u:
@@ -85,4 +105,55 @@ __restore_u:
     .endr
     addi sp, sp, 34*8
     csrr sp, uscratch
-    uret
\ No newline at end of file
+    uret
+    .globl __enter_user
+    .globl __leave_user
+# __enter_user(entry, user_sp, a2..a6) -> usize
+# Save the S-mode callee-saved registers and sstatus on the kernel stack, then
+# sret to `entry` in U-mode on `user_sp` with a2..a6 untouched. U-mode traps
+# are taken below the saved frame. `__leave_user(ret)` unwinds back to the
+# caller of `__enter_user`, which returns `ret`.
+    .align 2
+__enter_user:
+    addi sp, sp, -14*8
+    sd ra, 0*8(sp)
+    .set n, 0
+    .rept 12
+        SAVE_S %n
+        .set n, n+1
+    .endr
+    csrr t0, sstatus
+    sd t0, 13*8(sp)
+    # no S interrupt may see the U-mode sscratch while still in S-mode
+    csrci sstatus, 1 << 1
+    la t0, __user_return_sp
+    sd sp, 0(t0)
+    csrw sscratch, sp
+    csrw sepc, a0
+    mv sp, a1
+    # fresh frame chain for backtraces
+    mv s0, zero
+    # SPP = U
+    li t0, 1 << 8
+    csrc sstatus, t0
+    sret
+
+__leave_user:
+    csrw sscratch, zero
+    la t0, __user_return_sp
+    ld sp, 0(t0)
+    ld t0, 13*8(sp)
+    csrw sstatus, t0
+    ld ra, 0*8(sp)
+    .set n, 0
+    .rept 12
+        LOAD_S %n
+        .set n, n+1
+    .endr
+    addi sp, sp, 14*8
+    ret
+
+    .section .bss
+    .align 3
+__user_return_sp:
+    .space 8
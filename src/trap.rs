@@ -1,20 +1,18 @@
 use riscv::register::{
     mtvec::TrapMode,
     scause::{self},
-    sepc, sie, sip,
-    sstatus::Sstatus,
-    stval, stvec, ucause, uepc, uip,
-    ustatus::{self, Ustatus},
-    utval, utvec,
+    sie, sip, stvec,
+    ustatus::Ustatus,
 };
 
-use crate::sbi::{self, set_timer};
+use crate::{sbi::set_timer, uintr};
 use core::sync::atomic::Ordering::Relaxed;
 
 #[repr(C)]
 pub struct TrapContext {
     pub x: [usize; 32],
-    pub sstatus: Sstatus,
+    /// Raw `sstatus`, `__restore` writes it back
+    pub sstatus: usize,
     pub sepc: usize,
 }
 
@@ -25,33 +23,16 @@ pub struct UserTrapContext {
     pub uepc: usize,
 }
 
-impl UserTrapContext {
-    pub fn init(entry: usize, sp: usize, s: [usize; 12]) -> Self {
-        let mut ustatus = ustatus::read();
-        ustatus.set_upie(true);
-        let mut cx = Self {
-            x: [0; 32],
-            ustatus,
-            uepc: entry,
-        };
-        cx.x[2] = sp;
-        cx.x[8] = s[0];
-        cx.x[9] = s[1];
-        for i in 18..=27 {
-            cx.x[i] = s[i - 16];
-        }
-
-        cx
-    }
-}
-
 global_asm!(include_str!("trap.asm"));
 
 pub fn init() {
     extern "C" {
         fn __alltraps();
     }
+    register_defaults();
     unsafe {
+        // Traps from S-mode stay on the current stack, see trap.asm
+        write_csr!(0x140, 0);
         stvec::write(__alltraps as usize, TrapMode::Direct);
     }
 }
@@ -60,76 +41,258 @@ pub fn init_u() {
     extern "C" {
         fn __alltraps_u();
     }
+    match uintr::backend() {
+        Some(backend) => backend.set_vector(__alltraps_u as usize, TrapMode::Direct),
+        None => warn!("no user interrupts, U-mode trap vector left alone"),
+    }
+}
+
+/// `a7` of the ecall that ends a [`run_user`] session
+pub const USER_EXIT: usize = 0x0900_0000;
+
+extern "C" {
+    /// `a2`..`a6` reach `entry` untouched
+    fn __enter_user(
+        entry: usize,
+        sp: usize,
+        a2: usize,
+        a3: usize,
+        a4: usize,
+        a5: usize,
+        a6: usize,
+    ) -> usize;
+    fn __leave_user(ret: usize) -> !;
+}
+
+/// Jump to `entry` in U-mode on the stack `sp` and wait for it to issue an
+/// ecall with `a7 = USER_EXIT`. Returns the `a0` of that ecall.
+pub fn run_user(entry: usize, sp: usize) -> usize {
+    unsafe { __enter_user(entry, sp, 0, 0, 0, 0, 0) }
+}
+
+/// Signature accepted by [`call_user`]
+pub type UserFn = extern "C" fn(usize, usize, usize, usize) -> usize;
+
+/// First U-mode code of a [`call_user`] session
+extern "C" fn user_start(
+    _entry: usize,
+    _sp: usize,
+    f: UserFn,
+    a0: usize,
+    a1: usize,
+    a2: usize,
+    a3: usize,
+) -> ! {
+    exit_user(f(a0, a1, a2, a3))
+}
+
+/// Call `f(args)` in U-mode on the stack `sp` and return its result.
+pub fn call_user(f: UserFn, args: [usize; 4], sp: usize) -> usize {
+    let [a0, a1, a2, a3] = args;
+    unsafe { __enter_user(user_start as usize, sp, f as usize, a0, a1, a2, a3) }
+}
+
+/// Called from U-mode to end the [`run_user`] session with `ret`.
+pub fn exit_user(ret: usize) -> ! {
     unsafe {
-        utvec::write(__alltraps_u as usize, TrapMode::Direct);
+        asm!("ecall", in("a0") ret, in("a7") USER_EXIT, options(noreturn));
     }
 }
 
-#[no_mangle]
-pub fn trap_handler(cx: &mut TrapContext) -> &mut TrapContext {
-    let scause = scause::read();
-    let stval = stval::read();
-    match scause.cause() {
-        scause::Trap::Exception(scause::Exception::UserEnvCall) => {
-            cx.sepc += 4;
-            cx.x[10] = sbi::sbi_call(cx.x[17], cx.x[10], cx.x[11], cx.x[12]) as usize;
-        }
-        scause::Trap::Interrupt(scause::Interrupt::UserSoft) => {
-            debug!("user soft in supervisor");
-            unsafe {
-                sip::clear_usoft();
-            }
-        }
-        scause::Trap::Interrupt(scause::Interrupt::SupervisorSoft) => {
-            debug!("supervisor soft");
-            unsafe {
-                sip::clear_ssoft();
-            }
-        }
-        scause::Trap::Interrupt(scause::Interrupt::SupervisorExternal) => {
-            debug!("SEI");
-            crate::plic::handle_external_interrupt();
+/// Abandon the current trap and return from [`run_user`].
+pub fn leave_user(ret: usize) -> ! {
+    crate::crash::finish_user_fatal();
+    unsafe { __leave_user(ret) }
+}
+
+/// Raw `scause`/`ucause` values used as registry keys
+pub mod cause {
+    pub const INTERRUPT: usize = 1 << 63;
+
+    pub const USER_SOFT: usize = INTERRUPT;
+    pub const SUPERVISOR_SOFT: usize = INTERRUPT | 1;
+    pub const USER_TIMER: usize = INTERRUPT | 4;
+    pub const SUPERVISOR_TIMER: usize = INTERRUPT | 5;
+    pub const USER_EXTERNAL: usize = INTERRUPT | 8;
+    pub const SUPERVISOR_EXTERNAL: usize = INTERRUPT | 9;
+
+    pub const ILLEGAL_INSTRUCTION: usize = 2;
+    pub const BREAKPOINT: usize = 3;
+    pub const USER_ENV_CALL: usize = 8;
+}
+
+/// Handlers get the raw `scause` so one function can serve several causes
+pub type TrapHandler = fn(&mut TrapContext, usize);
+/// Runs in U-mode, gets the raw `ucause`
+pub type UserTrapHandler = fn(&mut UserTrapContext, usize);
+
+/// Exception and interrupt codes 0..16 each, interrupts in the upper half
+const HANDLER_SLOTS: usize = 32;
+
+static mut HANDLERS: [Option<TrapHandler>; HANDLER_SLOTS] = [None; HANDLER_SLOTS];
+static mut USER_HANDLERS: [Option<UserTrapHandler>; HANDLER_SLOTS] = [None; HANDLER_SLOTS];
+
+/// Take every cause without a handler of its own, see [`set_fallback`]
+static mut FALLBACK: Option<TrapHandler> = None;
+static mut USER_FALLBACK: Option<UserTrapHandler> = None;
+
+/// `None` for causes beyond the table, they always go to the fallback
+fn slot(cause: usize) -> Option<usize> {
+    let code = cause & !cause::INTERRUPT;
+    if code >= HANDLER_SLOTS / 2 {
+        return None;
+    }
+    Some(if cause & cause::INTERRUPT != 0 {
+        code + HANDLER_SLOTS / 2
+    } else {
+        code
+    })
+}
+
+/// Install (or with `None` remove) the S-mode handler for `cause`, returns
+/// the previous one so it can be put back afterwards.
+pub fn set_handler(cause: usize, handler: Option<TrapHandler>) -> Option<TrapHandler> {
+    match slot(cause) {
+        Some(slot) => unsafe { core::mem::replace(&mut HANDLERS[slot], handler) },
+        None => {
+            warn!("no handler slot for cause {:#x}", cause);
+            None
         }
-        scause::Trap::Interrupt(scause::Interrupt::SupervisorTimer) => {
-            debug!("supervisor timer");
-            crate::IS_TIMEOUT.store(true, Relaxed);
-            set_timer(usize::MAX);
-            unsafe {
-                sie::clear_stimer();
-            }
+    }
+}
+
+pub fn register(cause: usize, handler: TrapHandler) -> Option<TrapHandler> {
+    set_handler(cause, Some(handler))
+}
+
+/// Same as [`set_handler`] for `user_trap_handler`.
+pub fn set_user_handler(cause: usize, handler: Option<UserTrapHandler>) -> Option<UserTrapHandler> {
+    match slot(cause) {
+        Some(slot) => unsafe { core::mem::replace(&mut USER_HANDLERS[slot], handler) },
+        None => {
+            warn!("no user handler slot for cause {:#x}", cause);
+            None
         }
-        _ => {
-            error!(
-                "Unsupported trap {:?}, stval = {:#x}, sepc = {:#x}!",
-                scause.cause(),
-                stval,
-                sepc::read()
-            );
-            loop {}
+    }
+}
+
+pub fn register_user(cause: usize, handler: UserTrapHandler) -> Option<UserTrapHandler> {
+    set_user_handler(cause, Some(handler))
+}
+
+/// Install the handler for every cause nothing is registered for, returns
+/// the previous one.
+pub fn set_fallback(handler: TrapHandler) -> Option<TrapHandler> {
+    unsafe { FALLBACK.replace(handler) }
+}
+
+/// Same as [`set_fallback`] for `user_trap_handler`.
+pub fn set_user_fallback(handler: UserTrapHandler) -> Option<UserTrapHandler> {
+    unsafe { USER_FALLBACK.replace(handler) }
+}
+
+/// Copy of both registries, see [`save_handlers`]
+#[derive(Clone, Copy)]
+pub struct HandlerTable {
+    supervisor: [Option<TrapHandler>; HANDLER_SLOTS],
+    user: [Option<UserTrapHandler>; HANDLER_SLOTS],
+}
+
+/// Snapshot every registered handler, e.g. before running a test case.
+pub fn save_handlers() -> HandlerTable {
+    unsafe {
+        HandlerTable {
+            supervisor: HANDLERS,
+            user: USER_HANDLERS,
         }
     }
+}
+
+pub fn restore_handlers(table: HandlerTable) {
+    unsafe {
+        HANDLERS = table.supervisor;
+        USER_HANDLERS = table.user;
+    }
+}
+
+/// Install the built-in handlers, anything else ends up in the fallback.
+fn register_defaults() {
+    register(cause::USER_ENV_CALL, crate::syscall::handle);
+    register(cause::USER_SOFT, user_soft);
+    register(cause::SUPERVISOR_SOFT, supervisor_soft);
+    register(cause::SUPERVISOR_EXTERNAL, supervisor_external);
+    register(cause::SUPERVISOR_TIMER, supervisor_timer);
+    register_user(cause::USER_SOFT, user_soft_in_user);
+    register_user(cause::USER_EXTERNAL, user_external_in_user);
+    set_fallback(|cx, scause| crate::crash::fatal_trap(cx, scause));
+    set_user_fallback(|cx, ucause| crate::crash::fatal_user_trap(cx, ucause));
+}
+
+fn user_soft(_cx: &mut TrapContext, _scause: usize) {
+    debug!("user soft in supervisor");
+    unsafe {
+        sip::clear_usoft();
+    }
+}
+
+fn supervisor_soft(_cx: &mut TrapContext, _scause: usize) {
+    debug!("supervisor soft");
+    unsafe {
+        sip::clear_ssoft();
+    }
+}
+
+fn supervisor_external(_cx: &mut TrapContext, _scause: usize) {
+    debug!("SEI");
+    crate::plic::handle_external_interrupt();
+}
+
+fn supervisor_timer(_cx: &mut TrapContext, _scause: usize) {
+    debug!("supervisor timer");
+    crate::IS_TIMEOUT.store(true, Relaxed);
+    set_timer(usize::MAX);
+    unsafe {
+        sie::clear_stimer();
+    }
+}
+
+/// Only U-mode traps need it, and they can't happen without a backend
+fn user_interrupt() -> &'static dyn uintr::UserInterrupt {
+    uintr::backend().expect("U-mode trap without user interrupts")
+}
+
+fn user_soft_in_user(_cx: &mut UserTrapContext, _ucause: usize) {
+    debug!("user soft");
+    user_interrupt().complete(uintr::SOFT);
+}
+
+fn user_external_in_user(_cx: &mut UserTrapContext, _ucause: usize) {
+    debug!("UEI");
+    crate::plic::handle_user_external_interrupt();
+}
+
+#[no_mangle]
+pub fn trap_handler(cx: &mut TrapContext) -> &mut TrapContext {
+    let scause = scause::read().bits();
+    #[cfg(feature = "n_emul")]
+    if crate::emul::delegate_exception(cx, scause) {
+        return cx;
+    }
+    let handler = slot(scause).and_then(|slot| unsafe { HANDLERS[slot] });
+    // `init` installs the fallback before it points `stvec` here
+    let handler = handler.or(unsafe { FALLBACK }).unwrap();
+    handler(cx, scause);
+    crate::uirq::deliver(cx);
+    #[cfg(feature = "n_emul")]
+    crate::emul::deliver(cx);
     cx
 }
 
 #[no_mangle]
 pub fn user_trap_handler(cx: &mut UserTrapContext) -> &mut UserTrapContext {
-    let ucause = ucause::read();
-    let utval = utval::read();
-    match ucause.cause() {
-        ucause::Trap::Interrupt(ucause::Interrupt::UserSoft) => {
-            debug!("user soft");
-            unsafe {
-                uip::clear_usoft();
-            }
-        }
-        _ => {
-            error!(
-                "Unsupported trap {:?}, utval = {:#x}, uepc = {:#x}!",
-                ucause.cause(),
-                utval,
-                uepc::read()
-            );
-        }
-    }
+    let ucause = user_interrupt().claim();
+    let handler = slot(ucause).and_then(|slot| unsafe { USER_HANDLERS[slot] });
+    let handler = handler.or(unsafe { USER_FALLBACK }).unwrap();
+    handler(cx, ucause);
     cx
 }
@@ -0,0 +1,179 @@
+//! User-level interrupt mechanism behind a trait.
+//!
+//! Everything outside the conformance tests goes through [`backend`] instead
+//! of touching the N-extension CSRs, so another user-interrupt proposal can
+//! be plugged in and run against the same code. The tests in `tests::warl`,
+//! `tests::uret` etc. check the N extension itself and keep using raw CSRs.
+
+use crate::trap::UserTrapContext;
+use riscv::register::mtvec::TrapMode;
+
+/// Interrupt sources, as bits of `uie`/`uip`
+pub const SOFT: usize = 1 << 0;
+pub const TIMER: usize = 1 << 4;
+pub const EXTERNAL: usize = 1 << 8;
+
+/// Per-task copy of the user-level interrupt state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserState {
+    pub ustatus: usize,
+    pub uie: usize,
+    pub uip: usize,
+    pub utvec: usize,
+    pub uscratch: usize,
+    pub uepc: usize,
+    pub ucause: usize,
+    pub utval: usize,
+}
+
+impl UserState {
+    /// Take a trap with `ucause` at `epc` the way the hardware would, returns
+    /// the handler address to continue at. `None` if interrupts are disabled.
+    /// `uip` is left alone, see `uirq` for why.
+    pub fn enter_interrupt(&mut self, epc: usize, ucause: usize) -> Option<usize> {
+        const UIE: usize = 1 << 0;
+        const UPIE: usize = 1 << 4;
+        let code = ucause & !crate::trap::cause::INTERRUPT;
+        if self.ustatus & UIE == 0 || self.uie & (1 << code) == 0 {
+            return None;
+        }
+        self.uepc = epc;
+        self.ucause = ucause;
+        self.utval = 0;
+        self.ustatus = self.ustatus & !UIE | UPIE;
+        let base = self.utvec & !0b11;
+        Some(if self.utvec & 0b11 == 1 {
+            base + 4 * code
+        } else {
+            base
+        })
+    }
+}
+
+pub trait UserInterrupt: Sync {
+    fn name(&self) -> &'static str;
+    /// Route `sources` to U-mode instead of S-mode, S-mode only
+    fn delegate(&self, sources: usize);
+    fn undelegate(&self, sources: usize);
+    /// Where U-mode traps go
+    fn set_vector(&self, handler: usize, mode: TrapMode);
+    fn enable(&self, sources: usize);
+    fn disable(&self, sources: usize);
+    /// Global interrupt enable of U-mode
+    fn set_global(&self, enabled: bool);
+    /// Raise `sources` by software
+    fn pend(&self, sources: usize);
+    /// Raw cause of the U-mode trap being handled
+    fn claim(&self) -> usize;
+    /// Clear pending `sources` once they are handled
+    fn complete(&self, sources: usize);
+    /// Leave the U-mode trap handler, resuming from `cx`
+    fn restore(&self, cx: &UserTrapContext) -> !;
+    /// Snapshot everything that belongs to the running task, S-mode only
+    fn save_state(&self) -> UserState;
+    /// Install the state of the task about to run, S-mode only
+    fn load_state(&self, state: &UserState);
+}
+
+/// The N extension
+pub struct NExtension;
+
+impl UserInterrupt for NExtension {
+    fn name(&self) -> &'static str {
+        "N extension"
+    }
+
+    fn delegate(&self, sources: usize) {
+        unsafe { set_csr!(0x103, sources) }
+    }
+
+    fn undelegate(&self, sources: usize) {
+        unsafe { clear_csr!(0x103, sources) }
+    }
+
+    fn set_vector(&self, handler: usize, mode: TrapMode) {
+        unsafe { write_csr!(0x005, handler | mode as usize) }
+    }
+
+    fn enable(&self, sources: usize) {
+        unsafe { set_csr!(0x004, sources) }
+    }
+
+    fn disable(&self, sources: usize) {
+        unsafe { clear_csr!(0x004, sources) }
+    }
+
+    fn set_global(&self, enabled: bool) {
+        unsafe {
+            if enabled {
+                set_csr!(0x000, 1 << 0)
+            } else {
+                clear_csr!(0x000, 1 << 0)
+            }
+        }
+    }
+
+    fn pend(&self, sources: usize) {
+        unsafe { set_csr!(0x044, sources) }
+    }
+
+    fn claim(&self) -> usize {
+        unsafe { read_csr!(0x042) }
+    }
+
+    fn complete(&self, sources: usize) {
+        unsafe { clear_csr!(0x044, sources) }
+    }
+
+    fn restore(&self, cx: &UserTrapContext) -> ! {
+        extern "C" {
+            fn __restore_u(cx_addr: usize) -> !;
+        }
+        unsafe { __restore_u(cx as *const _ as usize) }
+    }
+
+    fn save_state(&self) -> UserState {
+        unsafe {
+            UserState {
+                ustatus: read_csr!(0x000),
+                uie: read_csr!(0x004),
+                uip: read_csr!(0x044),
+                utvec: read_csr!(0x005),
+                uscratch: read_csr!(0x040),
+                uepc: read_csr!(0x041),
+                ucause: read_csr!(0x042),
+                utval: read_csr!(0x043),
+            }
+        }
+    }
+
+    /// Only the software-writable bits of `uip` take, timer and external
+    /// follow their sources.
+    fn load_state(&self, state: &UserState) {
+        unsafe {
+            write_csr!(0x000, state.ustatus);
+            write_csr!(0x004, state.uie);
+            write_csr!(0x044, state.uip);
+            write_csr!(0x005, state.utvec);
+            write_csr!(0x040, state.uscratch);
+            write_csr!(0x041, state.uepc);
+            write_csr!(0x042, state.ucause);
+            write_csr!(0x043, state.utval);
+        }
+    }
+}
+
+pub static N_EXTENSION: NExtension = NExtension;
+
+/// `None` if the core has no user-level interrupts at all
+pub fn backend() -> Option<&'static dyn UserInterrupt> {
+    #[cfg(feature = "n_emul")]
+    if crate::emul::enabled() {
+        return Some(&crate::emul::EMULATED);
+    }
+    if crate::features::has_n_extension() {
+        Some(&N_EXTENSION)
+    } else {
+        None
+    }
+}
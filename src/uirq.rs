@@ -0,0 +1,217 @@
+//! External interrupts owned by U-mode tasks.
+//!
+//! A routed IRQ is claimed on the S-mode PLIC context like any other, then
+//! queued for its owner task instead of being completed. The owner takes a
+//! `UserExternal` trap, synthesized by the kernel, either right away if it
+//! is running or when it is next scheduled. Its handler gets the IRQ through
+//! `SYSCALL_IRQ_CLAIM`, `SYSCALL_IRQ_COMPLETE` completes it on the PLIC.
+//!
+//! `uip.UEIP` is not used for this: it is read-only and follows the U-mode
+//! PLIC context, which these IRQs never reach. The trap is faked instead by
+//! filling in `uepc`/`ucause` and pointing `sepc` at the handler.
+//!
+//! The time from claim in S-mode to claim by the owner is recorded, split by
+//! whether the owner was running when the IRQ arrived.
+
+use crate::{
+    plic::{self, Plic},
+    task::{self, MAX_TASKS},
+    trap::{cause, TrapContext},
+    uintr::{self, UserState},
+};
+use riscv::register::time;
+
+const MAX_ROUTES: usize = 8;
+const QUEUE_LEN: usize = 4;
+
+#[derive(Clone, Copy)]
+struct Queued {
+    irq: u16,
+    arrival: usize,
+    direct: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Queue {
+    items: [Option<Queued>; QUEUE_LEN],
+    /// Claimed by the owner, not completed yet
+    claimed: [Option<u16>; QUEUE_LEN],
+    /// A trap was injected and the owner has not completed it yet
+    in_handler: bool,
+}
+
+const EMPTY_QUEUE: Queue = Queue {
+    items: [None; QUEUE_LEN],
+    claimed: [None; QUEUE_LEN],
+    in_handler: false,
+};
+
+/// Claim latency in `time` ticks
+#[derive(Debug, Clone, Copy)]
+pub struct Latency {
+    pub count: usize,
+    pub total: usize,
+    pub max: usize,
+}
+
+impl Latency {
+    const fn new() -> Self {
+        Self {
+            count: 0,
+            total: 0,
+            max: 0,
+        }
+    }
+
+    fn record(&mut self, ticks: usize) {
+        self.count += 1;
+        self.total += ticks;
+        self.max = self.max.max(ticks);
+    }
+
+    pub fn average(&self) -> usize {
+        self.total.checked_div(self.count).unwrap_or(0)
+    }
+}
+
+static mut ROUTES: [Option<(u16, usize)>; MAX_ROUTES] = [None; MAX_ROUTES];
+static mut QUEUES: [Queue; MAX_TASKS] = [EMPTY_QUEUE; MAX_TASKS];
+static mut DIRECT: Latency = Latency::new();
+static mut DEFERRED: Latency = Latency::new();
+
+/// Hand `irq` to `task` from now on.
+pub fn route(irq: u16, task: usize) {
+    unroute(irq);
+    unsafe {
+        let slot = ROUTES.iter_mut().find(|r| r.is_none());
+        *slot.expect("too many routed IRQs") = Some((irq, task));
+    }
+}
+
+pub fn unroute(irq: u16) {
+    unsafe {
+        ROUTES
+            .iter_mut()
+            .filter(|r| matches!(r, Some((i, _)) if *i == irq))
+            .for_each(|r| *r = None);
+    }
+}
+
+/// Complete every IRQ still queued or claimed on the S-mode context, then
+/// forget every route and reset the latency counters.
+pub fn reset() {
+    let context = plic::get_context(0, 'S');
+    unsafe {
+        for queue in QUEUES.iter() {
+            let queued = queue.items.iter().flatten().map(|q| q.irq);
+            queued
+                .chain(queue.claimed.iter().copied().flatten())
+                .for_each(|irq| Plic::complete(context, irq));
+        }
+        ROUTES = [None; MAX_ROUTES];
+        QUEUES = [EMPTY_QUEUE; MAX_TASKS];
+        DIRECT = Latency::new();
+        DEFERRED = Latency::new();
+    }
+}
+
+fn owner(irq: u16) -> Option<usize> {
+    unsafe { ROUTES.iter().flatten().find(|(i, _)| *i == irq) }.map(|&(_, task)| task)
+}
+
+/// `(direct, deferred)`
+pub fn latency() -> (Latency, Latency) {
+    unsafe { (DIRECT, DEFERRED) }
+}
+
+/// Called by `plic::handle_external_interrupt` with a claimed IRQ. Returns
+/// `false` if nobody owns it and the kernel has to handle it.
+pub fn on_external(irq: u16) -> bool {
+    let task = match owner(irq) {
+        Some(task) => task,
+        None => return false,
+    };
+    let queued = Queued {
+        irq,
+        arrival: time::read(),
+        direct: task::current() == Some(task),
+    };
+    let queue = unsafe { &mut QUEUES[task] };
+    match queue.items.iter_mut().find(|q| q.is_none()) {
+        Some(slot) => *slot = Some(queued),
+        None => {
+            warn!("[UIRQ] queue of task {} full, IRQ {} dropped", task, irq);
+            Plic::complete(plic::get_context(0, 'S'), irq);
+        }
+    }
+    true
+}
+
+/// Redirect `cx` into the task's handler if it has IRQs waiting. `state` is
+/// the task's user state and `cx` the trap frame it resumes from.
+pub fn deliver_to(task: usize, state: &mut UserState, cx: &mut TrapContext) -> bool {
+    let queue = unsafe { &mut QUEUES[task] };
+    if queue.in_handler || queue.items[0].is_none() {
+        return false;
+    }
+    match state.enter_interrupt(cx.sepc, cause::USER_EXTERNAL) {
+        Some(handler) => {
+            cx.sepc = handler;
+            queue.in_handler = true;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Deliver to the running task on the way back to U-mode, called by
+/// `trap_handler` after the handler ran.
+pub fn deliver(cx: &mut TrapContext) {
+    let task = match task::current() {
+        Some(task) => task,
+        None => return,
+    };
+    let waiting = unsafe { QUEUES[task].items[0].is_some() && !QUEUES[task].in_handler };
+    if !waiting || cx.sstatus & (1 << 8) != 0 {
+        return;
+    }
+    if let Some(backend) = uintr::backend() {
+        let mut state = backend.save_state();
+        if deliver_to(task, &mut state, cx) {
+            backend.load_state(&state);
+        }
+    }
+}
+
+/// `SYSCALL_IRQ_CLAIM` of a task, `None` if it owns nothing pending
+pub fn claim(task: usize) -> Option<u16> {
+    let queue = unsafe { &mut QUEUES[task] };
+    let head = queue.items[0].take()?;
+    queue.items.rotate_left(1);
+    if let Some(slot) = queue.claimed.iter_mut().find(|c| c.is_none()) {
+        *slot = Some(head.irq);
+    }
+    let ticks = time::read() - head.arrival;
+    unsafe {
+        if head.direct {
+            DIRECT.record(ticks);
+        } else {
+            DEFERRED.record(ticks);
+        }
+    }
+    Some(head.irq)
+}
+
+/// `SYSCALL_IRQ_COMPLETE` of a task, `false` if it does not own `irq`
+pub fn complete(task: usize, irq: u16) -> bool {
+    if owner(irq) != Some(task) {
+        return false;
+    }
+    Plic::complete(plic::get_context(0, 'S'), irq);
+    let queue = unsafe { &mut QUEUES[task] };
+    if let Some(slot) = queue.claimed.iter_mut().find(|c| **c == Some(irq)) {
+        *slot = None;
+    }
+    queue.in_handler = false;
+    true
+}
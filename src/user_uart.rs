@@ -1,4 +1,7 @@
+use crate::plic;
+use core::cell::UnsafeCell;
 use core::convert::Infallible;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use embedded_hal::serial::{Read, Write};
 pub use serial_config::*;
 
@@ -10,6 +13,9 @@ mod serial_config {
     pub const SERIAL_NUM: usize = 4;
     pub const SERIAL_BASE_ADDRESS: usize = 0x1000_2000;
     pub const SERIAL_ADDRESS_STRIDE: usize = 0x1000;
+    /// Where register `i` is: `REG_OFFSET + i * REG_STRIDE`
+    pub const REG_OFFSET: usize = 0;
+    pub const REG_STRIDE: usize = 1;
     pub fn irq_to_serial_id(irq: u16) -> usize {
         match irq {
             12 => 0,
@@ -29,6 +35,10 @@ mod serial_config {
     pub const SERIAL_NUM: usize = 4;
     pub const SERIAL_BASE_ADDRESS: usize = 0x6000_1000;
     pub const SERIAL_ADDRESS_STRIDE: usize = 0x1000;
+    /// Where register `i` is: `REG_OFFSET + i * REG_STRIDE`, the AXI 16550
+    /// has 32-bit registers behind 4 KiB of its own
+    pub const REG_OFFSET: usize = 0x1000;
+    pub const REG_STRIDE: usize = 4;
     pub fn irq_to_serial_id(irq: u16) -> usize {
         match irq {
             4 => 0,
@@ -48,6 +58,7 @@ pub struct PollingSerial {
     pub hardware: SerialHardware,
     pub rx_count: usize,
     pub tx_count: usize,
+    /// Upper bound on the bytes in the Tx FIFO, only reset by `LSR::THRE`
     pub tx_fifo_count: usize,
 }
 
@@ -71,29 +82,39 @@ impl PollingSerial {
         // Rx FIFO trigger level=4, reset Rx & Tx FIFO, enable FIFO
         hardware.write_fcr(0b11_000_11_1);
     }
-
-    pub fn interrupt_handler(&mut self) {}
 }
 
 impl Write<u8> for PollingSerial {
     type Error = Infallible;
 
+    /// `WouldBlock` while the Tx FIFO may be full. The LSR is only read then,
+    /// in FIFO mode THRE means the whole FIFO drained.
     #[cfg(any(feature = "board_qemu", feature = "board_lrv"))]
     fn try_write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
-        let serial = &mut self.hardware;
-        while self.tx_fifo_count >= FIFO_DEPTH {
-            if serial.lsr().contains(LSR::THRE) {
-                self.tx_fifo_count = 0;
+        if self.tx_fifo_count >= FIFO_DEPTH {
+            if !self.hardware.lsr().contains(LSR::THRE) {
+                return Err(nb::Error::WouldBlock);
             }
+            self.tx_fifo_count = 0;
         }
-        serial.write_byte(word);
+        self.hardware.write_byte(word);
         self.tx_count += 1;
         self.tx_fifo_count += 1;
         Ok(())
     }
 
+    /// Done once TEMT reports both the FIFO and the shift register empty
+    #[cfg(any(feature = "board_qemu", feature = "board_lrv"))]
     fn try_flush(&mut self) -> nb::Result<(), Self::Error> {
-        todo!()
+        let lsr = self.hardware.lsr();
+        if lsr.contains(LSR::THRE) {
+            self.tx_fifo_count = 0;
+        }
+        if lsr.contains(LSR::TEMT) {
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
     }
 }
 
@@ -110,3 +131,334 @@ impl Read<u8> for PollingSerial {
         }
     }
 }
+
+/// Drives a non-blocking serial in place for code that wants to block,
+/// spinning on `WouldBlock`. Also usable with `write!`.
+pub struct Blocking<'a, S>(pub &'a mut S);
+
+impl<S: Write<u8>> Blocking<'_, S> {
+    pub fn write_all(&mut self, bytes: &[u8]) -> Result<(), S::Error> {
+        for &b in bytes {
+            nb::block!(self.0.try_write(b))?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), S::Error> {
+        nb::block!(self.0.try_flush())
+    }
+}
+
+impl<S: Read<u8>> Blocking<'_, S> {
+    pub fn read(&mut self) -> Result<u8, S::Error> {
+        nb::block!(self.0.try_read())
+    }
+
+    /// Fill all of `buf`
+    pub fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), S::Error> {
+        for b in buf.iter_mut() {
+            *b = self.read()?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Write<u8>> core::fmt::Write for Blocking<'_, S> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.write_all(s.as_bytes()).map_err(|_| core::fmt::Error)
+    }
+}
+
+/// Rx FIFO fill level that raises the data-available interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RxTrigger {
+    Bytes1 = 0b00,
+    Bytes4 = 0b01,
+    Bytes8 = 0b10,
+    Bytes14 = 0b11,
+}
+
+impl RxTrigger {
+    pub const ALL: [RxTrigger; 4] = [
+        RxTrigger::Bytes1,
+        RxTrigger::Bytes4,
+        RxTrigger::Bytes8,
+        RxTrigger::Bytes14,
+    ];
+
+    pub fn bytes(self) -> usize {
+        match self {
+            RxTrigger::Bytes1 => 1,
+            RxTrigger::Bytes4 => 4,
+            RxTrigger::Bytes8 => 8,
+            RxTrigger::Bytes14 => 14,
+        }
+    }
+}
+
+/// IER bits
+const IER_RDA: u8 = 1 << 0;
+const IER_THRE: u8 = 1 << 1;
+
+pub const BUFFER_SIZE: usize = 256;
+
+/// Single-producer single-consumer byte FIFO shared by a driver and its
+/// interrupt handler. Each slot is owned by exactly one side at a time, so
+/// neither needs to mask interrupts or hold a `&mut`. Which side is which is
+/// up to the owner, see [`push`](Self::push) and [`pop`](Self::pop).
+pub struct RingBuffer {
+    buf: [UnsafeCell<u8>; BUFFER_SIZE],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+/// Slots are handed between producer and consumer through `head`/`tail`,
+/// `push` and `pop` leave keeping each side unique to their callers
+unsafe impl Sync for RingBuffer {}
+
+impl RingBuffer {
+    pub const fn new() -> Self {
+        const EMPTY: UnsafeCell<u8> = UnsafeCell::new(0);
+        RingBuffer {
+            buf: [EMPTY; BUFFER_SIZE],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        tail.wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Producer side, gives `byte` back if the buffer is full
+    ///
+    /// # Safety
+    ///
+    /// Must not run concurrently with another `push` on the same buffer.
+    pub unsafe fn push(&self, byte: u8) -> Result<(), u8> {
+        if self.len() == BUFFER_SIZE {
+            return Err(byte);
+        }
+        let tail = self.tail.load(Ordering::Relaxed);
+        *self.buf[tail % BUFFER_SIZE].get() = byte;
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Consumer side
+    ///
+    /// # Safety
+    ///
+    /// Must not run concurrently with another `pop` on the same buffer.
+    pub unsafe fn pop(&self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let head = self.head.load(Ordering::Relaxed);
+        let byte = *self.buf[head % BUFFER_SIZE].get();
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(byte)
+    }
+}
+
+impl Default for RingBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Interrupt-driven serial: received bytes are drained into `rx_buffer` and
+/// `tx_buffer` is fed to the Tx FIFO whenever the THR empties.
+///
+/// The driver and its interrupt handler share it by `&`, `Read`/`Write` are
+/// implemented for `&BufferedSerial`. The handler is the only producer of
+/// `rx_buffer` and consumer of `tx_buffer`, readers and writers take turns
+/// on the other ends and get `WouldBlock` while another one is busy.
+pub struct BufferedSerial {
+    pub hardware: SerialHardware,
+    rx_buffer: RingBuffer,
+    tx_buffer: RingBuffer,
+    /// Claimed while a side of the buffers is in use, see [`claim`]
+    rx_busy: AtomicBool,
+    tx_busy: AtomicBool,
+    handler_busy: AtomicBool,
+    pub rx_count: AtomicUsize,
+    pub tx_count: AtomicUsize,
+    /// Received bytes lost because `rx_buffer` was full
+    pub rx_dropped: AtomicUsize,
+    pub intr_count: AtomicUsize,
+}
+
+impl BufferedSerial {
+    pub fn new(base_address: usize) -> Self {
+        BufferedSerial {
+            hardware: SerialHardware::new(base_address),
+            rx_buffer: RingBuffer::new(),
+            tx_buffer: RingBuffer::new(),
+            rx_busy: AtomicBool::new(false),
+            tx_busy: AtomicBool::new(false),
+            handler_busy: AtomicBool::new(false),
+            rx_count: AtomicUsize::new(0),
+            tx_count: AtomicUsize::new(0),
+            rx_dropped: AtomicUsize::new(0),
+            intr_count: AtomicUsize::new(0),
+        }
+    }
+
+    /// Same setup as [`PollingSerial::hardware_init`], then enable the Rx
+    /// interrupt. The THRE interrupt is only on while there is data to send.
+    pub fn hardware_init(&mut self, baud_rate: usize) {
+        let hardware = &mut self.hardware;
+        hardware.write_ier(0);
+        let _ = hardware.read_msr();
+        let _ = hardware.read_lsr();
+        hardware.init(100_000_000, baud_rate);
+        // Rx FIFO trigger level=4, reset Rx & Tx FIFO, enable FIFO
+        hardware.write_fcr(0b11_000_11_1);
+        hardware.write_ier(IER_RDA);
+    }
+
+    pub fn hardware_deinit(&self) {
+        self.hardware.write_ier(0);
+    }
+
+    /// Also resets both FIFOs
+    pub fn set_rx_trigger(&self, trigger: RxTrigger) {
+        self.hardware.write_fcr((trigger as u8) << 6 | 0b111);
+    }
+
+    /// Call once the PLIC reports this serial's IRQ, before completing it.
+    /// Does nothing if it interrupted another call of itself.
+    pub fn interrupt_handler(&self) {
+        claim(&self.handler_busy, || self.handle());
+    }
+
+    fn handle(&self) {
+        self.intr_count.fetch_add(1, Ordering::Relaxed);
+        // Rx data available or character timeout
+        while let Some(ch) = self.hardware.read_byte() {
+            // Only the handler pushes to `rx_buffer`
+            match unsafe { self.rx_buffer.push(ch) } {
+                Ok(()) => self.rx_count.fetch_add(1, Ordering::Relaxed),
+                Err(_) => self.rx_dropped.fetch_add(1, Ordering::Relaxed),
+            };
+        }
+        if self.hardware.lsr().contains(LSR::THRE) {
+            for _ in 0..FIFO_DEPTH {
+                // Only the handler pops from `tx_buffer`
+                match unsafe { self.tx_buffer.pop() } {
+                    Some(ch) => {
+                        self.hardware.write_byte(ch);
+                        self.tx_count.fetch_add(1, Ordering::Relaxed);
+                    }
+                    None => {
+                        self.hardware.write_ier(IER_RDA);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Write<u8> for &BufferedSerial {
+    type Error = Infallible;
+
+    fn try_write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        // `tx_busy` keeps writers apart
+        claim(&self.tx_busy, || unsafe { self.tx_buffer.push(word) })
+            .and_then(Result::ok)
+            .ok_or(nb::Error::WouldBlock)?;
+        self.hardware.write_ier(IER_RDA | IER_THRE);
+        Ok(())
+    }
+
+    fn try_flush(&mut self) -> nb::Result<(), Self::Error> {
+        if self.tx_buffer.is_empty() && self.hardware.lsr().contains(LSR::TEMT) {
+            Ok(())
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+}
+
+impl Read<u8> for &BufferedSerial {
+    type Error = Infallible;
+
+    fn try_read(&mut self) -> nb::Result<u8, Self::Error> {
+        // `rx_busy` keeps readers apart
+        claim(&self.rx_busy, || unsafe { self.rx_buffer.pop() })
+            .flatten()
+            .ok_or(nb::Error::WouldBlock)
+    }
+}
+
+/// Run `f` with `busy` held, `None` without running it if it already is.
+fn claim<T>(busy: &AtomicBool, f: impl FnOnce() -> T) -> Option<T> {
+    busy.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .ok()?;
+    let ret = f();
+    busy.store(false, Ordering::Release);
+    Some(ret)
+}
+
+/// `uart1` and `uart2`, driven by [`BufferedSerial`] from S- or U-mode
+#[cfg(feature = "board_qemu")]
+pub const BUFFERED_SERIAL_IRQS: [u16; 2] = [14, 15];
+#[cfg(feature = "board_lrv")]
+pub const BUFFERED_SERIAL_IRQS: [u16; 2] = [6, 7];
+
+static mut BUFFERED_SERIALS: [Option<BufferedSerial>; 2] = [None, None];
+/// PLIC context mode the IRQs are routed to
+static mut BUFFERED_MODE: char = 'S';
+
+/// Bring up the buffered serials with their IRQs on the PLIC context of
+/// `mode`, `'S'` or `'U'`. S-mode only, for `'U'` `uintr::EXTERNAL` still
+/// has to be delegated.
+pub fn init_buffered_serials(mode: char, baud_rate: usize) {
+    let serials = unsafe { BUFFERED_SERIALS.iter_mut() };
+    for (slot, &irq) in serials.zip(BUFFERED_SERIAL_IRQS.iter()) {
+        let mut serial = BufferedSerial::new(get_base_addr_from_irq(irq));
+        serial.hardware_init(baud_rate);
+        *slot = Some(serial);
+        plic::route(irq, mode);
+    }
+    unsafe { BUFFERED_MODE = mode };
+}
+
+/// Undo [`init_buffered_serials`], S-mode only
+pub fn deinit_buffered_serials() {
+    let serials = unsafe { BUFFERED_SERIALS.iter_mut() };
+    for (slot, &irq) in serials.zip(BUFFERED_SERIAL_IRQS.iter()) {
+        if let Some(serial) = slot.take() {
+            serial.hardware_deinit();
+        }
+        plic::unroute(irq, unsafe { BUFFERED_MODE });
+    }
+}
+
+/// `id` 0 is `uart1`, `None` before [`init_buffered_serials`]. Shared with
+/// the interrupt handler, so never handed out as `&mut`.
+pub fn buffered_serial(id: usize) -> Option<&'static BufferedSerial> {
+    unsafe { BUFFERED_SERIALS.get(id)?.as_ref() }
+}
+
+/// Called by the PLIC handler of either mode with a claimed IRQ, `false` if
+/// it is not one of ours.
+pub fn handle_irq(irq: u16) -> bool {
+    match BUFFERED_SERIAL_IRQS.iter().position(|&i| i == irq) {
+        Some(id) => match buffered_serial(id) {
+            Some(serial) => {
+                serial.interrupt_handler();
+                true
+            }
+            None => false,
+        },
+        None => false,
+    }
+}
@@ -0,0 +1,16 @@
+use std::{env, fs, path::Path};
+
+/// Programs in src/bin are linked at `USER_BASE_ADDRESS`, which must be free
+/// RAM above the kernel image on the target board.
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/linker.ld");
+    println!("cargo:rerun-if-env-changed=USER_BASE_ADDRESS");
+    let base = env::var("USER_BASE_ADDRESS").unwrap_or_else(|_| "0x80400000".into());
+    let script = fs::read_to_string("src/linker.ld")
+        .unwrap()
+        .replace("%BASE_ADDRESS%", &base);
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("linker.ld");
+    fs::write(&out, script).unwrap();
+    println!("cargo:rustc-link-arg-bins=-T{}", out.display());
+}
@@ -0,0 +1,30 @@
+//! Pend a user software interrupt a few times and count the handler runs.
+
+#![no_std]
+#![no_main]
+
+use core::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use user_lib::{println, trap, Source};
+
+const ROUNDS: usize = 3;
+
+static TAKEN: AtomicUsize = AtomicUsize::new(0);
+
+#[no_mangle]
+extern "C" fn main() -> usize {
+    trap::init();
+    trap::register(Source::Soft, |_| {
+        TAKEN.fetch_add(1, Relaxed);
+    });
+    trap::enable(Source::Soft);
+    for _ in 0..ROUNDS {
+        trap::pend(Source::Soft);
+    }
+    let taken = TAKEN.load(Relaxed);
+    println!("[uintr_soft] {} of {} interrupts taken", taken, ROUNDS);
+    if taken == ROUNDS {
+        0
+    } else {
+        1
+    }
+}
@@ -0,0 +1,33 @@
+use crate::syscall;
+use core::fmt::{self, Write};
+
+struct Stdout;
+
+impl Write for Stdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match syscall::write(syscall::STDOUT, s.as_bytes()) {
+            n if n < 0 => Err(fmt::Error),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Output the kernel refuses is dropped, panicking here would recurse
+/// through the panic handler.
+pub fn print(args: fmt::Arguments) {
+    let _ = Stdout.write_fmt(args);
+}
+
+#[macro_export]
+macro_rules! print {
+    ($fmt: literal $(, $($arg: tt)+)?) => {
+        $crate::console::print(format_args!($fmt $(, $($arg)+)?));
+    }
+}
+
+#[macro_export]
+macro_rules! println {
+    ($fmt: literal $(, $($arg: tt)+)?) => {
+        $crate::console::print(format_args!(concat!($fmt, "\r\n") $(, $($arg)+)?));
+    }
+}
@@ -0,0 +1,25 @@
+//! Runtime for U-mode test programs.
+//!
+//! Wraps the kernel syscalls, prints through `SYSCALL_WRITE` and owns the
+//! U-mode trap entry, so a program only registers closures for the user
+//! interrupts it cares about:
+//!
+//! ```ignore
+//! user_lib::trap::init();
+//! user_lib::trap::register(Source::Soft, |_| println!("soft"));
+//! user_lib::trap::enable(Source::Soft);
+//! user_lib::trap::pend(Source::Soft);
+//! ```
+
+#![no_std]
+#![feature(asm)]
+#![feature(global_asm)]
+
+#[macro_use]
+pub mod console;
+#[cfg(feature = "runtime")]
+mod runtime;
+pub mod syscall;
+pub mod trap;
+
+pub use trap::{Context, Source};
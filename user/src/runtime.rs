@@ -0,0 +1,23 @@
+//! Entry point of the standalone programs in `src/bin`. The kernel enters
+//! `_start` on a fresh user stack, the program provides
+//! `#[no_mangle] extern "C" fn main() -> usize` and its result becomes the
+//! exit code.
+
+use crate::syscall;
+use core::panic::PanicInfo;
+
+extern "C" {
+    fn main() -> usize;
+}
+
+#[no_mangle]
+#[link_section = ".text.entry"]
+extern "C" fn _start() -> ! {
+    syscall::exit(unsafe { main() })
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("[user_lib] {}", info);
+    syscall::exit(usize::MAX)
+}
@@ -0,0 +1,65 @@
+//! Kernel syscalls, numbers and ABI as in the kernel's `syscall.rs`.
+
+pub const SYSCALL_EXIT: usize = 0x0900_0000;
+pub const SYSCALL_WRITE: usize = 0x0900_0010;
+pub const SYSCALL_READ: usize = 0x0900_0011;
+pub const SYSCALL_YIELD: usize = 0x0900_0012;
+pub const SYSCALL_GET_TIME: usize = 0x0900_0013;
+pub const SYSCALL_UINTR_REGISTER: usize = 0x0900_0020;
+pub const SYSCALL_IRQ_CLAIM: usize = 0x0900_0021;
+pub const SYSCALL_IRQ_COMPLETE: usize = 0x0900_0022;
+
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+
+fn syscall(id: usize, args: [usize; 3]) -> isize {
+    let ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") args[0] => ret,
+            in("a1") args[1],
+            in("a2") args[2],
+            in("a7") id,
+        );
+    }
+    ret
+}
+
+pub fn exit(code: usize) -> ! {
+    syscall(SYSCALL_EXIT, [code, 0, 0]);
+    unreachable!("exit returned")
+}
+
+pub fn write(fd: usize, buf: &[u8]) -> isize {
+    syscall(SYSCALL_WRITE, [fd, buf.as_ptr() as usize, buf.len()])
+}
+
+/// Does not block, returns how many bytes were read
+pub fn read(fd: usize, buf: &mut [u8]) -> isize {
+    syscall(SYSCALL_READ, [fd, buf.as_mut_ptr() as usize, buf.len()])
+}
+
+pub fn yield_() -> isize {
+    syscall(SYSCALL_YIELD, [0; 3])
+}
+
+/// In `time` ticks
+pub fn get_time() -> isize {
+    syscall(SYSCALL_GET_TIME, [0; 3])
+}
+
+/// Have the kernel's U-mode trap entry call `handler` for `ucause`. Not
+/// needed with [`crate::trap::init`], which installs the library's own entry.
+pub fn uintr_register(ucause: usize, handler: usize) -> isize {
+    syscall(SYSCALL_UINTR_REGISTER, [ucause, handler, 0])
+}
+
+/// Claim on the U-mode PLIC context, 0 if nothing is pending
+pub fn irq_claim() -> isize {
+    syscall(SYSCALL_IRQ_CLAIM, [0; 3])
+}
+
+pub fn irq_complete(irq: usize) -> isize {
+    syscall(SYSCALL_IRQ_COMPLETE, [irq, 0, 0])
+}
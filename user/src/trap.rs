@@ -0,0 +1,216 @@
+//! U-mode trap entry and closure registry for the user interrupts.
+//!
+//! Closures are stored inline, there is no allocator: anything capturing
+//! more than [`SLOT_WORDS`] words is rejected.
+
+use core::mem::{align_of, size_of, MaybeUninit};
+
+const UIE: usize = 1 << 0;
+const INTERRUPT: usize = 1 << 63;
+
+/// Saved by `__user_lib_trap`
+#[repr(C)]
+pub struct Context {
+    pub x: [usize; 32],
+    pub ustatus: usize,
+    pub uepc: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Soft,
+    Timer,
+    External,
+}
+
+impl Source {
+    /// Interrupt code, also the bit in `uie`/`uip`
+    pub fn code(self) -> usize {
+        match self {
+            Source::Soft => 0,
+            Source::Timer => 4,
+            Source::External => 8,
+        }
+    }
+
+    fn from_code(code: usize) -> Option<Self> {
+        match code {
+            0 => Some(Source::Soft),
+            4 => Some(Source::Timer),
+            8 => Some(Source::External),
+            _ => None,
+        }
+    }
+}
+
+global_asm!(
+    "
+    .altmacro
+    .macro USER_LIB_SAVE n
+        sd x\\n, \\n*8(sp)
+    .endm
+    .macro USER_LIB_LOAD n
+        ld x\\n, \\n*8(sp)
+    .endm
+    .section .text
+    .globl __user_lib_trap
+    .align 2
+__user_lib_trap:
+    csrw uscratch, sp
+    addi sp, sp, -34*8
+    sd x1, 1*8(sp)
+    sd x3, 3*8(sp)
+    .set n, 5
+    .rept 27
+        USER_LIB_SAVE %n
+        .set n, n+1
+    .endr
+    csrr t0, ustatus
+    csrr t1, uepc
+    sd t0, 32*8(sp)
+    sd t1, 33*8(sp)
+    csrr t2, uscratch
+    sd t2, 2*8(sp)
+    mv a0, sp
+    call user_lib_trap_handler
+    mv sp, a0
+    ld t0, 32*8(sp)
+    ld t1, 33*8(sp)
+    ld t2, 2*8(sp)
+    csrw ustatus, t0
+    csrw uepc, t1
+    csrw uscratch, t2
+    ld x1, 1*8(sp)
+    ld x3, 3*8(sp)
+    .set n, 5
+    .rept 27
+        USER_LIB_LOAD %n
+        .set n, n+1
+    .endr
+    addi sp, sp, 34*8
+    csrr sp, uscratch
+    uret
+"
+);
+
+/// Words of captured state a closure may carry
+pub const SLOT_WORDS: usize = 8;
+
+struct Slot {
+    data: MaybeUninit<[usize; SLOT_WORDS]>,
+    call: Option<unsafe fn(*mut usize, &mut Context)>,
+    drop: unsafe fn(*mut usize),
+}
+
+unsafe fn call_closure<F: FnMut(&mut Context)>(data: *mut usize, cx: &mut Context) {
+    (*(data as *mut F))(cx)
+}
+
+unsafe fn drop_closure<F>(data: *mut usize) {
+    core::ptr::drop_in_place(data as *mut F)
+}
+
+unsafe fn drop_nothing(_: *mut usize) {}
+
+const EMPTY: Slot = Slot {
+    data: MaybeUninit::uninit(),
+    call: None,
+    drop: drop_nothing,
+};
+
+static mut SLOTS: [Slot; 3] = [EMPTY, EMPTY, EMPTY];
+
+fn slot(source: Source) -> &'static mut Slot {
+    unsafe { &mut SLOTS[source.code() / 4] }
+}
+
+/// Run `f` with `ustatus.UIE` clear so no handler sees a half-written slot.
+fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    let ustatus: usize;
+    unsafe { asm!("csrrc {0}, ustatus, {1}", out(reg) ustatus, in(reg) UIE) };
+    let ret = f();
+    if ustatus & UIE != 0 {
+        unsafe { asm!("csrs ustatus, {0}", in(reg) UIE) };
+    }
+    ret
+}
+
+/// Point `utvec` at the library's entry and enable user interrupts.
+pub fn init() {
+    extern "C" {
+        fn __user_lib_trap();
+    }
+    unsafe {
+        asm!("csrw utvec, {0}", in(reg) __user_lib_trap as usize);
+        asm!("csrs ustatus, {0}", in(reg) UIE);
+    }
+}
+
+/// Call `f` whenever `source` is taken, replacing the previous closure.
+pub fn register<F>(source: Source, f: F)
+where
+    F: FnMut(&mut Context) + Send + 'static,
+{
+    assert!(
+        size_of::<F>() <= size_of::<[usize; SLOT_WORDS]>()
+            && align_of::<F>() <= align_of::<usize>(),
+        "closure too big for a handler slot"
+    );
+    without_interrupts(|| {
+        unregister(source);
+        let slot = slot(source);
+        unsafe { (slot.data.as_mut_ptr() as *mut F).write(f) };
+        slot.call = Some(call_closure::<F>);
+        slot.drop = drop_closure::<F>;
+    })
+}
+
+pub fn unregister(source: Source) {
+    without_interrupts(|| {
+        let slot = slot(source);
+        if slot.call.take().is_some() {
+            unsafe { (slot.drop)(slot.data.as_mut_ptr() as *mut usize) };
+        }
+        slot.drop = drop_nothing;
+    })
+}
+
+pub fn enable(source: Source) {
+    unsafe { asm!("csrs uie, {0}", in(reg) 1usize << source.code()) };
+}
+
+pub fn disable(source: Source) {
+    unsafe { asm!("csrc uie, {0}", in(reg) 1usize << source.code()) };
+}
+
+/// Only [`Source::Soft`] can be raised from U-mode
+pub fn pend(source: Source) {
+    unsafe { asm!("csrs uip, {0}", in(reg) 1usize << source.code()) };
+}
+
+/// Soft is cleared before the closure runs, timer and external have to be
+/// acknowledged by the closure at their source.
+#[no_mangle]
+extern "C" fn user_lib_trap_handler(cx: &mut Context) -> &mut Context {
+    let ucause: usize;
+    unsafe { asm!("csrr {0}, ucause", out(reg) ucause) };
+    let source = match ucause & INTERRUPT {
+        0 => None,
+        _ => Source::from_code(ucause & !INTERRUPT),
+    };
+    let slot = match source {
+        Some(source) => slot(source),
+        None => {
+            println!("[user_lib] unexpected trap, ucause = {:#x}", ucause);
+            crate::syscall::exit(usize::MAX)
+        }
+    };
+    if source == Some(Source::Soft) {
+        unsafe { asm!("csrc uip, {0}", in(reg) 1usize) };
+    }
+    match slot.call {
+        Some(call) => unsafe { call(slot.data.as_mut_ptr() as *mut usize, cx) },
+        None => println!("[user_lib] unhandled {:?}", source.unwrap()),
+    }
+    cx
+}